@@ -1,17 +1,26 @@
-use std::fs;
-use std::fs::{File,OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::error::Error;
+use std::io::{BufRead, BufWriter, ErrorKind, Write};
+use log::{debug, info};
+use crate::error::{R2dError, create_file};
 use std::collections::HashMap;
-use crate::parse_gtf::{Transcript, read_annotation_file, Exon};
+use crate::parse_gtf::{Transcript, read_annotation_file};
+use crate::liftover::transcript_to_genomic_position;
+use crate::isoforms::{ExonIndex, IsoformPolicy, isoform_policy_from_matches};
+use crate::landmarks::LandmarkSet;
+use crate::tracks::{GenomicTrack, tracks_from_matches};
+use crate::input::{BATCH_SIZE, RowValidator, SiteLayout, check_unique_labels, coord_base_from_matches, input_file_from_matches, input_name, named_file, open_input, read_batch, input_format_from_matches, site_layout_from_matches};
+use crate::sequence::{SequenceSource, matches_motif, gc_content, is_valid_motif, translate_codon};
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct SpliceSite {
     pub transcript_id: String,
     pub tx_coord: u64,
+    // 0-based genomic positions of the last base of the upstream exon and the first base of the downstream exon
+    pub donor: u64,
+    pub acceptor: u64,
+    // genomic length of the intron between them
+    pub intron_len: u64,
 }
 
 fn calculate_cds_start(utr5_len: u64) -> u64 {
@@ -22,17 +31,59 @@ fn calculate_cds_end(utr5_len: u64, cds_len: u64) -> u64 {
     utr5_len + cds_len
 }
 
+// handling of sites in a 5'UTR, CDS or 3'UTR of zero length, where the metacoordinate has no defined value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ZeroLengthPolicy {
+    // report NA for transcript_metacoordinate
+    Na,
+    // place the site on the boundary where the segment starts
+    Clamp,
+    // leave the site out of the output
+    Drop,
+}
+
+fn zero_length_policy_from_matches(matches: &clap::ArgMatches) -> Result<ZeroLengthPolicy, R2dError> {
+    match matches.get_one::<String>("zero-length-segments").map(|policy| policy.as_str()) {
+        None | Some("na") => Ok(ZeroLengthPolicy::Na),
+        Some("clamp") => Ok(ZeroLengthPolicy::Clamp),
+        Some("drop") => Ok(ZeroLengthPolicy::Drop),
+        Some(other) => Err(R2dError::InputParse(format!("Invalid zero-length segment policy: {}", other))),
+    }
+}
+
+// metatranscript segment containing the site (0: 5'UTR, 1: CDS, 2: 3'UTR), when that segment has zero length
+// e.g. a site in the stop codon of a transcript without an annotated 3'UTR
+fn zero_length_segment(tx_coord: u64, utr5_len: u64, cds_len: u64, utr3_len: u64) -> Option<u64> {
+    let (segment, segment_len) = if tx_coord < utr5_len {
+        (0, utr5_len)
+    } else if tx_coord < utr5_len + cds_len {
+        (1, cds_len)
+    } else {
+        (2, utr3_len)
+    };
+    (segment_len == 0).then_some(segment)
+}
+
+// relative position within a metatranscript segment, with zero-length segments collapsed onto their start
+fn segment_fraction(offset: u64, segment_len: u64) -> f64 {
+    if segment_len == 0 {
+        return 0.0;
+    }
+    offset as f64 / segment_len as f64
+}
+
+// tx_coord is the 0-based transcript coordinate of the site, whatever the convention of the input
 fn calculate_meta_coordinates(tx_coord: u64, utr5_len: u64, cds_len: u64, utr3_len: u64) -> (f64, i64, i64) {
     let cds_start = utr5_len;
     let cds_end = utr5_len + cds_len;
     let _tx_end = cds_end + utr3_len;
 
     let rel_pos = if tx_coord < cds_start {
-        tx_coord as f64 / cds_start as f64
+        segment_fraction(tx_coord, cds_start)
     } else if tx_coord < cds_end {
-        1.0 + (tx_coord - utr5_len) as f64 / cds_len as f64
+        1.0 + segment_fraction(tx_coord - utr5_len, cds_len)
     } else {
-        2.0 + (tx_coord - utr5_len - cds_len) as f64 / utr3_len as f64
+        2.0 + segment_fraction(tx_coord - utr5_len - cds_len, utr3_len)
     };
 
     let abs_cds_start = tx_coord as i64 - cds_start as i64;
@@ -42,57 +93,253 @@ fn calculate_meta_coordinates(tx_coord: u64, utr5_len: u64, cds_len: u64, utr3_l
 }
 
 
-fn generate_splice_sites(transcripts: &HashMap<String, Transcript>) -> Arc<Mutex<HashMap<String, Vec<SpliceSite>>>> {
-    let splice_sites_map = Arc::new(Mutex::new(HashMap::new()));
+// exon-exon junctions of each transcript, keyed like the annotations
+// each junction is the 0-based transcript coordinate of the first base of the downstream exon
+fn generate_splice_sites(transcripts: &HashMap<String, Transcript>) -> HashMap<String, Vec<SpliceSite>> {
+    transcripts.par_iter().map(|(transcript_id, transcript)| {
+        let exons = transcript.ordered_exons();
+        let mut cumulative_length: u64 = 0;
+        let mut splice_sites: Vec<SpliceSite> = Vec::new();
 
-    let file_path = "splice_sites_map.txt";
-    let file = OpenOptions::new().write(true).create(true).truncate(true).open(file_path).expect("Unable to open file");
-    let file = Arc::new(Mutex::new(file));
+        // the last exon has no downstream junction
+        for pair in exons.windows(2) {
+            let (exon, next_exon) = (pair[0], pair[1]);
+            cumulative_length += exon.length;
+            // exons are 1-based; on the minus strand the transcript runs towards lower coordinates
+            let (donor, acceptor, intron_len) = if transcript.strand.as_deref() == Some("-") {
+                (exon.start - 1, next_exon.end - 1, exon.start.saturating_sub(next_exon.end + 1))
+            } else {
+                (exon.end - 1, next_exon.start - 1, next_exon.start.saturating_sub(exon.end + 1))
+            };
+            splice_sites.push(SpliceSite {
+                transcript_id: transcript_id.clone(),
+                tx_coord: cumulative_length,
+                donor,
+                acceptor,
+                intron_len,
+            });
+        }
 
-    transcripts.par_iter().for_each(|(transcript_id, transcript)| {
-        let transcript_id_no_version = transcript_id.split('.').next().unwrap().to_string();
-        let mut splice_sites: Vec<SpliceSite> = Vec::new();
-        let mut cumulative_length: u64 = 0;
+        (transcript_id.clone(), splice_sites)
+    }).collect()
+}
 
-        if !transcript.exons.is_empty() {
-            let mut exons: Vec<&Exon> = transcript.exons.iter()
-                .filter(|exon| exon.feature.as_ref().map_or(false, |ft| ft == "exon"))
-                .collect();
+// write the junction table as a TSV, one row per junction in transcript order
+fn dump_junctions(file_path: &str, splice_sites: &HashMap<String, Vec<SpliceSite>>) -> Result<(), R2dError> {
+    let mut writer = BufWriter::new(create_file(file_path)?);
+    writeln!(writer, "transcript_id\tjunction_number\ttx_coord\tdonor\tacceptor\tintron_len")?;
 
-            if transcript.strand.as_deref() == Some("+") {
-                exons.sort_by_key(|exon| exon.start);
-            } else if transcript.strand.as_deref() == Some("-") {
-                exons.sort_by_key(|exon| std::cmp::Reverse(exon.start));
-            }
+    let mut transcript_ids: Vec<&String> = splice_sites.keys().collect();
+    transcript_ids.sort();
+    for transcript_id in transcript_ids {
+        for (i, splice_site) in splice_sites[transcript_id].iter().enumerate() {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                splice_site.transcript_id, i + 1, splice_site.tx_coord, splice_site.donor, splice_site.acceptor, splice_site.intron_len
+            )?;
+        }
+    }
 
-            for (i, exon) in exons.iter().enumerate() {
+    writer.flush()?;
+    Ok(())
+}
 
-                // Update cumulative length to include exon length
-                cumulative_length += exon.length;
-                if i < exons.len() - 1 {  // Ignore the last exon 
-                    splice_sites.push(SpliceSite {
-                        transcript_id: transcript_id_no_version.clone(),
-                        tx_coord: cumulative_length,  // Position within the transcript
-                    });
-                }
-                // Debug output 
-                // println!("Transcript ID: {}, Exon Length: {}, Cumulative Length: {}", transcript_id_no_version, exon.length, cumulative_length);
-            }
+// region of the mature transcript containing the site, following the segments of the metatranscript
+// transcripts without a CDS are labelled non_coding, and sites beyond the transcript end NA
+fn site_region(transcript: &Transcript, tx_coord: u64) -> &'static str {
+    match transcript.transcript_length {
+        Some(tx_len) if tx_coord < tx_len => {}
+        _ => return "NA",
+    }
+    let cds_len = transcript.cds_len.unwrap_or(0);
+    if cds_len == 0 {
+        return "non_coding";
+    }
+    let utr5_len = transcript.utr5_len.unwrap_or(0);
+    if tx_coord < utr5_len {
+        "5UTR"
+    } else if tx_coord < utr5_len + cds_len {
+        "CDS"
+    } else {
+        "3UTR"
+    }
+}
+
+// position of an interval site (e.g. a peak or window) used for its annotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SiteAnchor {
+    Start,
+    // last base of the interval
+    End,
+    // central base of the interval, the upstream one of the two central bases of an even-length interval
+    Midpoint,
+}
+
+impl SiteAnchor {
+    fn position(self, start: u64, end: u64) -> u64 {
+        match self {
+            SiteAnchor::Start => start,
+            SiteAnchor::End => end - 1,
+            SiteAnchor::Midpoint => start + (end - start - 1) / 2,
         }
+    }
+}
+
+fn site_anchor_from_matches(matches: &clap::ArgMatches) -> Result<SiteAnchor, R2dError> {
+    match matches.get_one::<String>("anchor").map(|anchor| anchor.as_str()) {
+        None | Some("start") => Ok(SiteAnchor::Start),
+        Some("end") => Ok(SiteAnchor::End),
+        Some("midpoint") => Ok(SiteAnchor::Midpoint),
+        Some(other) => Err(R2dError::InputParse(format!("Invalid site anchor: {}", other))),
+    }
+}
+
+// length of a 0-based, half-open transcript interval, its bases in the 5'UTR, CDS and 3'UTR (NA for transcripts
+// without a CDS; bases beyond the transcript end are not counted) and whether it spans an exon-exon junction
+fn interval_composition_fields(start: u64, end: u64, transcript: &Transcript, splice_sites: Option<&Vec<SpliceSite>>) -> Vec<String> {
+    let mut fields = vec![(end - start).to_string()];
 
-        // Writing to file (thread-safe)
-        let mut file = file.lock().unwrap();
-        writeln!(file, "Transcript ID: {}", transcript_id_no_version).expect("Unable to write to file");
-        for splice_site in &splice_sites {
-            writeln!(file, "Splice Site: {}", splice_site.tx_coord).expect("Unable to write to file");
+    let overlap = |segment_start: u64, segment_end: u64| end.min(segment_end).saturating_sub(start.max(segment_start));
+    match (transcript.cds_len.filter(|&cds_len| cds_len > 0), transcript.transcript_length) {
+        (Some(cds_len), Some(tx_len)) => {
+            let cds_start = transcript.utr5_len.unwrap_or(0);
+            let cds_end = (cds_start + cds_len).min(tx_len);
+            fields.push(overlap(0, cds_start).to_string());
+            fields.push(overlap(cds_start, cds_end).to_string());
+            fields.push(overlap(cds_end, tx_len).to_string());
         }
-        writeln!(file).expect("Unable to write to file"); // Write a newline for better readability
+        _ => fields.extend(["NA", "NA", "NA"].map(String::from)),
+    }
 
-        let mut map = splice_sites_map.lock().unwrap();
-        map.insert(transcript_id_no_version, splice_sites);
-    });
+    // a junction lies within the interval when the first base after it does, other than the interval start
+    let spans_junction = splice_sites.is_some_and(|sites| sites.iter().any(|site| start < site.tx_coord && site.tx_coord < end));
+    fields.push(if spans_junction { "TRUE" } else { "FALSE" }.to_string());
 
-    splice_sites_map
+    fields
+}
+
+// exon containing the site, found from the junctions of the transcript
+#[derive(Debug, PartialEq)]
+struct ExonPosition {
+    // 1-based, counted in transcript order
+    number: usize,
+    length: u64,
+    // 0-based offset of the site within the exon, divided by the exon length
+    rel_pos: f64,
+    is_first: bool,
+    is_last: bool,
+}
+
+fn exon_position(tx_coord: u64, splice_sites: &[SpliceSite], tx_len: u64) -> Option<ExonPosition> {
+    if tx_coord >= tx_len {
+        return None;
+    }
+    // each junction is the first base of the next exon
+    let index = splice_sites.partition_point(|splice_site| splice_site.tx_coord <= tx_coord);
+    let exon_start = if index == 0 { 0 } else { splice_sites[index - 1].tx_coord };
+    let exon_end = splice_sites.get(index).map_or(tx_len, |splice_site| splice_site.tx_coord);
+    let length = exon_end - exon_start;
+    Some(ExonPosition {
+        number: index + 1,
+        length,
+        rel_pos: (tx_coord - exon_start) as f64 / length as f64,
+        is_first: index == 0,
+        is_last: index == splice_sites.len(),
+    })
+}
+
+// relative position of the site along the mature transcript, from 0 at the first base to 1 at the transcript end
+// unlike transcript_metacoordinate it needs no CDS, so it also covers non-coding transcripts
+fn noncoding_metacoordinate(tx_coord: u64, tx_len: u64) -> Option<f64> {
+    if tx_coord >= tx_len {
+        return None;
+    }
+    Some(tx_coord as f64 / tx_len as f64)
+}
+
+// reading frame (0, 1 or 2) of a CDS site within its codon, and the 1-based number of the codon from the start codon
+fn codon_position(transcript: &Transcript, tx_coord: u64) -> Option<(u64, u64)> {
+    let cds_len = transcript.cds_len.filter(|&cds_len| cds_len > 0)?;
+    let utr5_len = transcript.utr5_len.unwrap_or(0);
+    if tx_coord < utr5_len || tx_coord >= utr5_len + cds_len {
+        return None;
+    }
+    let cds_offset = tx_coord - utr5_len;
+    Some((cds_offset % 3, cds_offset / 3 + 1))
+}
+
+// geometry of the junctions flanking the exon that contains the site: up_intron_len, down_intron_len,
+// up_exon_len, down_exon_len, up_donor, up_acceptor, down_donor and down_acceptor
+// genomic coordinates are given in the coordinate convention of the input
+fn junction_geometry_fields(tx_coord: u64, splice_sites: &[SpliceSite], tx_len: u64, coord_base: u64) -> Vec<String> {
+    let mut fields = vec!["NA".to_string(); 8];
+    let Some(exon) = exon_position(tx_coord, splice_sites, tx_len) else {
+        return fields;
+    };
+    let index = exon.number - 1;
+    let junction_coord = |i: usize| if i == 0 { 0 } else { splice_sites[i - 1].tx_coord };
+
+    if index > 0 {
+        let upstream = &splice_sites[index - 1];
+        fields[0] = upstream.intron_len.to_string();
+        fields[2] = (upstream.tx_coord - junction_coord(index - 1)).to_string();
+        fields[4] = (upstream.donor + coord_base).to_string();
+        fields[5] = (upstream.acceptor + coord_base).to_string();
+    }
+    if let Some(downstream) = splice_sites.get(index) {
+        let next_exon_end = splice_sites.get(index + 1).map_or(tx_len, |splice_site| splice_site.tx_coord);
+        fields[1] = downstream.intron_len.to_string();
+        fields[3] = (next_exon_end - downstream.tx_coord).to_string();
+        fields[6] = (downstream.donor + coord_base).to_string();
+        fields[7] = (downstream.acceptor + coord_base).to_string();
+    }
+    fields
+}
+
+// distances along the mature transcript to its landmarks: tss_dist, tx_end_dist and last_junc_dist (negative
+// upstream of the last exon-exon junction), and nmd_50nt, whether the stop codon lies more than 50 nt upstream
+// of the last junction so that the transcript is a candidate for nonsense-mediated decay
+fn transcript_landmark_fields(tx_coord: u64, tx_len: u64, splice_sites: &[SpliceSite], cds_end: Option<u64>) -> Vec<String> {
+    let mut fields = vec!["NA".to_string(); 4];
+    if tx_coord < tx_len {
+        fields[0] = tx_coord.to_string();
+        fields[1] = (tx_len - 1 - tx_coord).to_string();
+        if let Some(last_junction) = splice_sites.last() {
+            fields[2] = (tx_coord as i64 - last_junction.tx_coord as i64).to_string();
+        }
+    }
+    // cds_end is the first base after the CDS, i.e. the stop codon when the CDS features exclude it
+    if let Some(cds_end) = cds_end {
+        let is_nmd_candidate = splice_sites.last()
+            .is_some_and(|last_junction| last_junction.tx_coord as i64 - cds_end as i64 > 50);
+        fields[3] = is_nmd_candidate.to_string().to_uppercase();
+    }
+    fields
+}
+
+// region, exon and codon columns: region, exon_number, exon_len, exon_rel_pos, first_exon, last_exon,
+// frame and codon_number
+fn site_structure_fields(transcript: &Transcript, tx_coord: u64, splice_sites: Option<&Vec<SpliceSite>>) -> Vec<String> {
+    let mut fields = vec![site_region(transcript, tx_coord).to_string()];
+    let exon = match (splice_sites, transcript.transcript_length) {
+        (Some(splice_sites), Some(tx_len)) => exon_position(tx_coord, splice_sites, tx_len),
+        _ => None,
+    };
+    match exon {
+        Some(exon) => fields.extend([
+            exon.number.to_string(),
+            exon.length.to_string(),
+            format!("{:.5}", exon.rel_pos),
+            exon.is_first.to_string().to_uppercase(),
+            exon.is_last.to_string().to_uppercase(),
+        ]),
+        None => fields.extend(std::iter::repeat_n("NA".to_string(), 5)),
+    }
+    match codon_position(transcript, tx_coord) {
+        Some((frame, codon_number)) => fields.extend([frame.to_string(), codon_number.to_string()]),
+        None => fields.extend(["NA".to_string(), "NA".to_string()]),
+    }
+    fields
 }
 
 fn splice_site_distances(tx_coord: u64, splice_sites: &[SpliceSite]) -> (Option<i64>, Option<i64>) {
@@ -127,19 +374,318 @@ fn splice_site_distances(tx_coord: u64, splice_sites: &[SpliceSite]) -> (Option<
     (upstream_distance, downstream_distance)
 }
 
+// settings for the sequence context columns
+struct ContextSettings {
+    source: SequenceSource,
+    flank: i64,
+    motif: Option<(String, i64)>,
+}
 
-pub fn run_annotate(matches: &clap::ArgMatches, has_header: bool, has_version: bool) -> Result<(), Box<dyn Error>> {
+// sequence context columns: +/- flank nt around the site, gc content of the window, and optional motif match
+fn parse_context_settings(matches: &clap::ArgMatches, source: SequenceSource) -> Result<ContextSettings, R2dError> {
+    let invalid_number = |n: &String| R2dError::InputParse(format!("Invalid number of nucleotides: {}", n));
+    // --context defaults to 10 nt, and negative values are rejected
+    let context = matches.get_one::<String>("context").unwrap();
+    let flank = context.parse::<u64>().map_err(|_| invalid_number(context))? as i64;
+
+    let motif = match matches.get_one::<String>("motif") {
+        Some(motif) => {
+            if !is_valid_motif(motif) {
+                return Err(R2dError::InputParse(format!("Invalid IUPAC motif: {}", motif)));
+            }
+            // by default the site is placed on the central base of the motif (e.g. the A of DRACH)
+            let offset: i64 = matches.get_one::<String>("motif-offset")
+                .map_or(Ok(motif.len() as i64 / 2), |n| n.parse().map_err(|_| invalid_number(n)))?;
+            if offset < 0 || offset >= motif.len() as i64 {
+                return Err(R2dError::InputParse(format!("Motif offset {} is outside of motif {}", offset, motif)));
+            }
+            Some((motif.to_uppercase(), offset))
+        }
+        None => None,
+    };
+
+    Ok(ContextSettings { source, flank, motif })
+}
+
+fn sequence_context_fields(settings: &ContextSettings, transcript: &Transcript, tx_coord: u64) -> Vec<String> {
+    let site = tx_coord as i64;
+    let mut fields = Vec::new();
+
+    // codon containing a CDS site and the amino acid it encodes
+    let codon = codon_position(transcript, tx_coord).and_then(|(frame, _)| {
+        let codon_start = site - frame as i64;
+        settings.source.fetch(transcript, codon_start, codon_start + 3)
+    });
+    match codon {
+        Some(codon) => {
+            fields.push(String::from_utf8_lossy(&codon).into_owned());
+            fields.push((translate_codon(&codon) as char).to_string());
+        }
+        None => fields.extend(["NA".to_string(), "NA".to_string()]),
+    }
+
+    match settings.source.fetch(transcript, site - settings.flank, site + settings.flank + 1) {
+        Some(window) => {
+            fields.push(String::from_utf8_lossy(&window).into_owned());
+            fields.push(gc_content(&window).map_or("NA".to_string(), |gc| format!("{:.5}", gc)));
+        }
+        None => fields.extend(["NA".to_string(), "NA".to_string()]),
+    }
+
+    if let Some((motif, offset)) = &settings.motif {
+        let motif_start = site - offset;
+        let motif_match = settings.source.fetch(transcript, motif_start, motif_start + motif.len() as i64)
+            .map_or("NA".to_string(), |kmer| matches_motif(&kmer, motif).to_string().to_uppercase());
+        fields.push(motif_match);
+    }
+
+    fields
+}
+
+
+// read-only tables shared by the threads annotating a batch of rows
+struct AnnotationContext<'a> {
+    transcripts: &'a HashMap<String, Transcript>,
+    splice_sites: &'a HashMap<String, Vec<SpliceSite>>,
+    context_settings: Option<&'a ContextSettings>,
+    extra_column_count: usize,
+    coord_base: Option<u64>,
+    has_version: bool,
+    genomic: bool,
+    // report noncoding_metacoordinate for coding transcripts too
+    all_noncoding_metacoordinates: bool,
+    zero_length_policy: ZeroLengthPolicy,
+    landmarks: &'a [LandmarkSet],
+    tracks: &'a [GenomicTrack],
+    anchor: SiteAnchor,
+    interval_composition: bool,
+}
+
+// columns added to every input row, before the optional columns
+const ANNOTATION_COLUMNS: [&str; 33] = [
+    "gene_id", "gene_name", "transcript_biotype", "tx_len", "cds_start", "cds_end", "tx_end",
+    "transcript_metacoordinate", "abs_cds_start", "abs_cds_end", "up_junc_dist", "down_junc_dist",
+    "region", "exon_number", "exon_len", "exon_rel_pos", "first_exon", "last_exon", "frame", "codon_number",
+    "noncoding_metacoordinate", "up_intron_len", "down_intron_len", "up_exon_len", "down_exon_len",
+    "up_donor", "up_acceptor", "down_donor", "down_acceptor", "tss_dist", "tx_end_dist", "last_junc_dist", "nmd_50nt",
+];
+
+// outcome of annotating a single input row
+enum AnnotatedRow {
+    // the output line, and whether the site matched an annotated transcript
+    Output(String, bool),
+    // the site lies in a zero-length segment and is left out with --zero-length-segments drop
+    Dropped,
+}
+
+// annotate one input row against the shared tables
+fn annotate_row(context: &AnnotationContext, layout: &SiteLayout, line: &str) -> Result<Vec<AnnotatedRow>, String> {
+    let fields: Vec<&str> = layout.split(line.trim_end_matches('\r'));
+    let record = layout.validate_record(&fields)?;
+    let (start, end) = layout.validate_interval(&fields, record.position)?;
+
+    let transcript_id = if context.has_version {
+        record.transcript_id
+    } else {
+        record.transcript_id.split('.').next().unwrap()
+    };
+
+    Ok(vec![annotate_site(context, transcript_id, start, end, record.columns)])
+}
+
+// annotate a genomic input row against each overlapping isoform, with the transcript and transcript position
+// of the site after the input columns; sites without an overlapping isoform are written once with NA annotations
+fn annotate_genomic_row(
+    context: &AnnotationContext,
+    exon_index: &ExonIndex,
+    isoform_policy: IsoformPolicy,
+    layout: &SiteLayout,
+    line: &str
+) -> Result<Vec<AnnotatedRow>, String> {
+    let fields: Vec<&str> = layout.split(line.trim_end_matches('\r'));
+    let record = layout.validate_record(&fields)?;
+    // the strand of BED6 sites, when given, restricts the isoforms to those of the same strand
+    let strand = fields.get(5).map(|strand| strand.trim()).filter(|&strand| strand == "+" || strand == "-");
+
+    let hits = exon_index.overlapping(context.transcripts, record.transcript_id, record.position, strand, isoform_policy);
+    if hits.is_empty() {
+        return Ok(vec![annotate_site(context, "", record.position, record.position + 1, format!("{}\tNA\tNA", record.columns))]);
+    }
+    Ok(hits.iter()
+        .map(|hit| {
+            let columns = format!("{}\t{}\t{}", record.columns, hit.transcript_id, hit.tx_coord + context.coord_base.unwrap_or(0));
+            annotate_site(context, hit.transcript_id, hit.tx_coord, hit.tx_coord + 1, columns)
+        })
+        .collect())
+}
+
+// annotate the 0-based, half-open transcript interval of a site, appending the annotation columns to its input columns
+fn annotate_site(context: &AnnotationContext, transcript_id: &str, start: u64, end: u64, line: String) -> AnnotatedRow {
+    let tx_coord = context.anchor.position(start, end);
+
+    let mut output_line;
+    let is_annotated;
+    if let Some(transcript) = context.transcripts.get(transcript_id) {
+        is_annotated = true;
+
+        // Initialize all fields to "NA"
+        let mut cds_start = "NA".to_string();
+        let mut cds_end = "NA".to_string();
+        let mut tx_end = "NA".to_string();
+        let mut rel_pos = "NA".to_string();
+        let mut abs_cds_start = "NA".to_string();
+        let mut abs_cds_end = "NA".to_string();
+        let mut up_junc_dist = "NA".to_string();
+        let mut down_junc_dist = "NA".to_string();
+
+        // Always populate gene_id, gene_name, and biotype if available
+        let gene_id = transcript.gene_id.clone().unwrap_or_else(|| "NA".to_string());
+        let gene_name = transcript.gene_name.clone().unwrap_or_else(|| "NA".to_string());
+        let biotype = transcript.biotype.clone().unwrap_or_else(|| "NA".to_string());
+
+        let tx_len = transcript.transcript_length.map_or("NA".to_string(), |len| len.to_string());
+        // a missing 5'UTR or 3'UTR of a coding transcript is a segment of zero length
+        let coding_segments = transcript.cds_len.filter(|&cds_len| cds_len > 0)
+            .map(|cds_len| (transcript.utr5_len.unwrap_or(0), cds_len, transcript.utr3_len.unwrap_or(0)));
+        if let Some((utr5_len, cds_len, utr3_len)) = coding_segments {
+            cds_start = calculate_cds_start(utr5_len).to_string();
+            cds_end = calculate_cds_end(utr5_len, cds_len).to_string();
+            tx_end = tx_len.clone();
+            let calculated_values = calculate_meta_coordinates(tx_coord, utr5_len, cds_len, utr3_len);
+            rel_pos = format!("{:.5}", calculated_values.0);
+            if zero_length_segment(tx_coord, utr5_len, cds_len, utr3_len).is_some() {
+                match context.zero_length_policy {
+                    ZeroLengthPolicy::Na => rel_pos = "NA".to_string(),
+                    ZeroLengthPolicy::Clamp => {}
+                    ZeroLengthPolicy::Drop => return AnnotatedRow::Dropped,
+                }
+            }
+            abs_cds_start = calculated_values.1.to_string();
+            abs_cds_end = calculated_values.2.to_string();
+        }
+
+        // Handle splice sites if available
+        if let Some(splice_sites) = context.splice_sites.get(transcript_id) {
+            let calculated_distances = splice_site_distances(tx_coord, splice_sites);
+            up_junc_dist = calculated_distances.0.map_or("NA".to_string(), |x| x.to_string());
+            down_junc_dist = calculated_distances.1.map_or("NA".to_string(), |x| x.to_string());
+        }
+
+        // Construct the output line
+        output_line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            line,
+            gene_id,
+            gene_name,
+            biotype,
+            tx_len,
+            cds_start,
+            cds_end,
+            tx_end,
+            rel_pos,
+            abs_cds_start,
+            abs_cds_end,
+            up_junc_dist,
+            down_junc_dist
+        );
+        for field in site_structure_fields(transcript, tx_coord, context.splice_sites.get(transcript_id)) {
+            output_line.push('\t');
+            output_line.push_str(&field);
+        }
+        let is_coding = coding_segments.is_some();
+        let noncoding_rel_pos = match transcript.transcript_length {
+            Some(len) if !is_coding || context.all_noncoding_metacoordinates => noncoding_metacoordinate(tx_coord, len),
+            _ => None,
+        };
+        output_line.push_str(&format!("\t{}", noncoding_rel_pos.map_or("NA".to_string(), |x| format!("{:.5}", x))));
+        let geometry = match (context.splice_sites.get(transcript_id), transcript.transcript_length) {
+            (Some(splice_sites), Some(len)) => junction_geometry_fields(tx_coord, splice_sites, len, context.coord_base.unwrap_or(0)),
+            _ => vec!["NA".to_string(); 8],
+        };
+        for field in geometry {
+            output_line.push('\t');
+            output_line.push_str(&field);
+        }
+        let landmarks = match (context.splice_sites.get(transcript_id), transcript.transcript_length) {
+            (Some(splice_sites), Some(len)) => {
+                let cds_end = coding_segments.map(|(utr5_len, cds_len, _)| calculate_cds_end(utr5_len, cds_len));
+                transcript_landmark_fields(tx_coord, len, splice_sites, cds_end)
+            }
+            _ => vec!["NA".to_string(); 4],
+        };
+        for field in landmarks {
+            output_line.push('\t');
+            output_line.push_str(&field);
+        }
+        if context.genomic {
+            // genomic position of the site, in the coordinate convention of the input
+            let genomic_position = transcript_to_genomic_position(transcript, tx_coord)
+                .map_or("NA".to_string(), |position| (position + context.coord_base.unwrap_or(0)).to_string());
+            let strand = transcript.strand.as_deref().unwrap_or("NA");
+            output_line.push_str(&format!("\t{}\t{}\t{}", transcript.chromosome, genomic_position, strand));
+        }
+        for landmark_set in context.landmarks {
+            match landmark_set.nearest(transcript_id, tx_coord) {
+                Some((distance, name)) => output_line.push_str(&format!("\t{}\t{}", distance, name)),
+                None => output_line.push_str("\tNA\tNA"),
+            }
+        }
+        if !context.tracks.is_empty() {
+            // genomic features overlapping the lifted site, NA for positions beyond the transcript end
+            let genomic_position = transcript_to_genomic_position(transcript, tx_coord);
+            for track in context.tracks {
+                match genomic_position {
+                    Some(position) => {
+                        for field in track.fields(&transcript.chromosome, position) {
+                            output_line.push('\t');
+                            output_line.push_str(&field);
+                        }
+                    }
+                    None => output_line.push_str("\tNA\tNA"),
+                }
+            }
+        }
+        if context.interval_composition {
+            for field in interval_composition_fields(start, end, transcript, context.splice_sites.get(transcript_id)) {
+                output_line.push('\t');
+                output_line.push_str(&field);
+            }
+        }
+        if let Some(settings) = context.context_settings {
+            for field in sequence_context_fields(settings, transcript, tx_coord) {
+                output_line.push('\t');
+                output_line.push_str(&field);
+            }
+        }
+    } else {
+        // Handle the case where no transcript data is found
+        is_annotated = false;
+        output_line = format!("{}{}", line, "\tNA".repeat(ANNOTATION_COLUMNS.len()));
+        for _ in 0..context.extra_column_count {
+            output_line.push_str("\tNA");
+        }
+    }
+    if let Some(base) = context.coord_base {
+        output_line.push_str(&format!("\t{}", base));
+    }
+    AnnotatedRow::Output(output_line, is_annotated)
+}
+
+pub fn run_annotate(matches: &clap::ArgMatches, has_header: bool, has_version: bool) -> Result<(), R2dError> {
    
     // eprintln!("Running the annotate functionality...");
    
     let gtf_file: String = matches.get_one::<String>("gtf").unwrap().to_string();
-    let input_file: String = matches.get_one::<String>("input").unwrap().to_string();
+    let input_file = input_file_from_matches(matches);
     let output_file: Option<String> = matches.get_one::<String>("output").map(|s: &String| s.to_string());
 
     // By default, read in the annotations as GTF file
     // TODO: implement GFF3 parsing         
     // let default_format = String::from("gtf");
     // let format = matches.get_one("format").unwrap_or(&default_format);
+    let coord_base = coord_base_from_matches(matches)?;
+    // landmark and track labels share the output column names
+    check_unique_labels(matches, &["landmarks", "track"])?;
     let annotations = read_annotation_file(&gtf_file, true, has_version)?;
     
     // Print the annotations in a table
@@ -148,111 +694,181 @@ pub fn run_annotate(matches: &clap::ArgMatches, has_header: bool, has_version: b
 
     let transcripts = annotations;
 
-    let mut input_reader = BufReader::new(File::open(input_file.clone()).unwrap_or_else(|_| panic!("Cannot open input file: {}", input_file)));
+    let mut input_reader = open_input(&input_file)?;
 
-    let mut output_writer: Box<dyn Write> = match output_file {
-        Some(file) => Box::new(File::create(file).expect("Cannot create output file")),
-        None => Box::new(std::io::stdout()),
-    };
+    let mut output_writer = BufWriter::with_capacity(512 * 1024, match output_file {
+        Some(file_name) => Box::new(create_file(&file_name)?) as Box<dyn Write>,
+        None => Box::new(std::io::stdout()) as Box<dyn Write>,
+    });
 
     let splice_sites = generate_splice_sites(&transcripts);
+    if let Some(junctions_file) = matches.get_one::<String>("dump-junctions") {
+        dump_junctions(junctions_file, &splice_sites)?;
+    }
 
-    let mut header = String::new();
-    if has_header {
-        input_reader.read_line(&mut header).unwrap();
-        let header_fields: Vec<&str> = header.trim().split('\t').collect();
+    // optional sequence context from a transcriptome or genome fasta
+    let sequence_source = if let Some(fasta_file) = matches.get_one::<String>("fasta") {
+        Some(SequenceSource::from_transcriptome(fasta_file, has_version)?)
+    } else if let Some(genome_file) = matches.get_one::<String>("genome") {
+        Some(SequenceSource::from_genome(genome_file, &transcripts)?)
+    } else {
+        None
+    };
+    let context_settings = match sequence_source {
+        Some(source) => Some(parse_context_settings(matches, source)?),
+        None => None,
+    };
+
+    // genomic coordinates of the sites, lifted over from the same transcript catalogue
+    let genomic = matches.get_flag("genomic");
+    let mut extra_columns: Vec<String> = Vec::new();
+    if genomic {
+        extra_columns.extend(["chromosome", "genomic_position", "strand"].map(String::from));
+    }
 
-        let output_header = format!(
-    "{}\tgene_id\tgene_name\ttranscript_biotype\ttx_len\tcds_start\tcds_end\ttx_end\ttranscript_metacoordinate\tabs_cds_start\tabs_cds_end\tup_junc_dist\tdown_junc_dist",
-    header_fields.join("\t")
-);
-        writeln!(output_writer, "{}", output_header).unwrap();
+    // nearest user-supplied landmark of each BED file, in transcript coordinates
+    let mut landmarks = Vec::new();
+    for spec in matches.get_many::<String>("landmarks").unwrap_or_default() {
+        let (label, file_path) = named_file(spec);
+        extra_columns.extend([format!("{}_dist", label), format!("{}_name", label)]);
+        landmarks.push(LandmarkSet::from_file(&label, &file_path, has_version)?);
     }
 
-    for line in input_reader.lines() {
-        let line = line.unwrap();
-        let fields: Vec<&str> = line.split('\t').collect();
-        let transcript_id_with_version = fields[0];
+    // overlap with, and distance to, the features of each genomic track
+    let tracks = tracks_from_matches(matches)?;
+    extra_columns.extend(tracks.iter().flat_map(|track| track.columns()));
 
+    // interval sites (e.g. peaks) can be annotated by a position other than their start, and described as a whole
+    let anchor = site_anchor_from_matches(matches)?;
+    let interval_composition = matches.get_flag("interval-composition");
+    if interval_composition {
+        extra_columns.extend(["interval_len", "utr5_bases", "cds_bases", "utr3_bases", "spans_junction"].map(String::from));
+    }
+    if let Some(settings) = &context_settings {
+        extra_columns.extend(["codon", "amino_acid", "seq_context", "gc_content"].map(String::from));
+        if settings.motif.is_some() {
+            extra_columns.push("motif_match".to_string());
+        }
+    }
 
-        let transcript_id = if has_version {
-            transcript_id_with_version
-        } else {
-            transcript_id_with_version.split('.').next().unwrap()
-        };
-    
+    // the tables of modification callers carry their own header row
+    let has_header = input_format_from_matches(matches)?.has_header().unwrap_or(has_header);
+    let mut header = String::new();
+    if has_header {
+        input_reader.read_line(&mut header)?;
+    }
+    // the id and position columns may be selected by name, so the layout is resolved after reading the header
+    let mut layout = site_layout_from_matches(matches, if has_header { Some(header.as_str()) } else { None })?;
+    // BED intervals end in the column after their start; the sites of caller formats are single bases
+    if (anchor != SiteAnchor::Start || interval_composition) && layout.kept_columns.is_none() {
+        layout.end_column = Some(layout.position_column + 1);
+    }
 
-        let tx_coord: u64 = fields[1].parse().unwrap();
-    
-        if let Some(transcript) = transcripts.get(transcript_id) {
-            
-            // Initialize all fields to "NA"
-            let tx_len;
-            let mut cds_start = "NA".to_string();
-            let mut cds_end = "NA".to_string();
-            let mut tx_end = "NA".to_string();
-            let mut rel_pos = "NA".to_string();
-            let mut abs_cds_start = "NA".to_string();
-            let mut abs_cds_end = "NA".to_string();
-            let mut up_junc_dist = "NA".to_string();
-            let mut down_junc_dist = "NA".to_string(); 
-        
-            // Always populate gene_id, gene_name, and biotype if available
-            let gene_id = transcript.gene_id.clone().unwrap_or_else(|| "NA".to_string());
-            let gene_name = transcript.gene_name.clone().unwrap_or_else(|| "NA".to_string());
-            let biotype = transcript.biotype.clone().unwrap_or_else(|| "NA".to_string());
-    
-            tx_len = transcript.transcript_length.map_or("NA".to_string(), |len| len.to_string());
-            if let (Some(utr5_len), Some(cds_len), Some(utr3_len)) = (transcript.utr5_len, transcript.cds_len, transcript.utr3_len) {
-                cds_start = calculate_cds_start(utr5_len).to_string();
-                cds_end = calculate_cds_end(utr5_len, cds_len).to_string();
-                tx_end = tx_len.clone();
-                let calculated_values = calculate_meta_coordinates(tx_coord, utr5_len, cds_len, utr3_len);
-                rel_pos = format!("{:.5}", calculated_values.0);
-                abs_cds_start = calculated_values.1.to_string();
-                abs_cds_end = calculated_values.2.to_string();
-            }
+    // genomic sites are annotated against the isoforms with an exon containing them
+    let genomic_input = matches.get_flag("genomic-input");
+    let isoform_policy = isoform_policy_from_matches(matches)?;
+    let exon_index = if genomic_input { Some(ExonIndex::new(&transcripts)) } else { None };
 
-            // Lock the mutex to safely access the shared hashmap
-            let map_lock = splice_sites.lock().expect("Failed to lock the mutex");
+    if let Some(input_header) = layout.output_header(if has_header { Some(header.as_str()) } else { None }) {
+        let isoform_columns = if genomic_input { "\ttranscript_id\ttx_coord" } else { "" };
+        let mut output_header = format!("{}{}\t{}", input_header, isoform_columns, ANNOTATION_COLUMNS.join("\t"));
+        for column in &extra_columns {
+            output_header.push('\t');
+            output_header.push_str(column);
+        }
+        if coord_base.is_some() {
+            output_header.push_str("\tcoord_base");
+        }
+        writeln!(output_writer, "{}", output_header)?;
+    }
 
-            // Handle splice sites if available
-            if let Some(splice_sites) = map_lock.get(transcript_id) {
-                let calculated_distances = splice_site_distances(tx_coord, splice_sites);
-                up_junc_dist = calculated_distances.0.map_or("NA".to_string(), |x| x.to_string());
-                down_junc_dist = calculated_distances.1.map_or("NA".to_string(), |x| x.to_string());
-            }
-    
-            // Construct and write the output line
-            let output_line = format!(
-                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                line,
-                gene_id,
-                gene_name,
-                biotype,
-                tx_len,
-                cds_start,
-                cds_end,
-                tx_end,
-                rel_pos,
-                abs_cds_start,
-                abs_cds_end,
-                up_junc_dist,
-                down_junc_dist
-            );
-            if let Err(_) = writeln!(output_writer, "{}", output_line) {
-                break;
+    let context = AnnotationContext {
+        transcripts: &transcripts,
+        splice_sites: &splice_sites,
+        context_settings: context_settings.as_ref(),
+        extra_column_count: extra_columns.len(),
+        coord_base,
+        has_version,
+        genomic,
+        all_noncoding_metacoordinates: matches.get_flag("all-noncoding-metacoordinates"),
+        zero_length_policy: zero_length_policy_from_matches(matches)?,
+        landmarks: &landmarks,
+        tracks: &tracks,
+        anchor,
+        interval_composition,
+    };
+
+    // malformed rows are skipped and counted, or stop the run with --strict
+    let mut validator = RowValidator::from_matches(matches, input_name(&input_file));
+    let first_line = if has_header { 2 } else { 1 };
+    let mut site_count = 0;
+    let mut annotated_count = 0;
+    let mut dropped_count = 0;
+    let mut lines = input_reader.lines().enumerate().map(|(index, line)| (index + first_line, line));
+    let mut batch: Vec<(usize, String)> = Vec::with_capacity(BATCH_SIZE);
+    // read a batch of rows, then annotate it in parallel against the shared read-only tables
+    'batches: while read_batch(&mut lines, &mut batch)? {
+        let annotated_rows: Vec<_> = batch.par_iter()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(line_number, line)| {
+                let rows = match &exon_index {
+                    Some(exon_index) => annotate_genomic_row(&context, exon_index, isoform_policy, &layout, line),
+                    None => annotate_row(&context, &layout, line),
+                };
+                (*line_number, rows)
+            })
+            .collect();
+
+        // rows are validated and written in input order
+        for (line_number, result) in annotated_rows {
+            let Some(rows) = validator.check(line_number, result)? else {
+                continue;
+            };
+            site_count += 1;
+            let mut is_site_annotated = false;
+            for row in rows {
+                match row {
+                    AnnotatedRow::Output(output_line, is_annotated) => {
+                        is_site_annotated |= is_annotated;
+                        if let Err(e) = writeln!(output_writer, "{}", output_line) {
+                            // a closed pipe (e.g. into head) ends the output early without an error
+                            if e.kind() == ErrorKind::BrokenPipe {
+                                break 'batches;
+                            }
+                            return Err(e.into());
+                        }
+                    }
+                    AnnotatedRow::Dropped => {
+                        is_site_annotated = true;
+                        dropped_count += 1;
+                    }
+                }
             }
-        } else {
-            // Handle the case where no transcript data is found
-            if let Err(_) = writeln!(output_writer, "{}\tNA\tNA\tNA\tNA\tNA\tNA\tNA\tNA\tNA\tNA\tNA\tNA", line) {
-                break;
+            if is_site_annotated {
+                annotated_count += 1;
             }
         }
-    }    
-    let file_path = "splice_sites_map.txt";
-    if let Err(e) = fs::remove_file(file_path) {
-        eprintln!("Failed to delete file '{}': {:?}", file_path, e);
+    }
+    match output_writer.flush() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    validator.report();
+    if dropped_count > 0 {
+        info!("Dropped {} sites in a 5'UTR, CDS or 3'UTR of zero length", dropped_count);
+    }
+
+    // sites are still written with NA annotations, but none matching usually means the wrong GTF or version flag
+    if annotated_count == 0 && site_count > 0 {
+        let hint = if genomic_input {
+            "the genome assembly and its chromosome names"
+        } else {
+            "the transcriptome and the -t/--transcript-version flag"
+        };
+        return Err(R2dError::Mapping(format!(
+            "none of the {} sites in {} matched an annotated transcript, check that the GTF matches {}",
+            site_count, input_name(&input_file), hint
+        )));
     }
 
     Ok(())
@@ -260,12 +876,12 @@ pub fn run_annotate(matches: &clap::ArgMatches, has_header: bool, has_version: b
 
 
 pub fn preview_annotations(annotations: &HashMap<String, Transcript>) {
-    eprintln!("Number of annotations: {}", annotations.len()); 
+    debug!("Number of annotations: {}", annotations.len()); 
     for (key, transcript) in annotations {
-        eprintln!("Annotations start");
-        eprintln!("Transcript ID: {}", key);
-        eprintln!("{:#?}", transcript);
-        eprintln!("Annotations end");
+        debug!("Annotations start");
+        debug!("Transcript ID: {}", key);
+        debug!("{:#?}", transcript);
+        debug!("Annotations end");
     }
 }
 
@@ -275,6 +891,35 @@ mod tests {
     use super::*;
     use tempfile::NamedTempFile;
 
+    // mock of the annotate subcommand arguments
+    fn create_test_command() -> clap::Command {
+        clap::Command::new("test")
+            .arg(clap::Arg::new("gtf").short('g').long("gtf").required(true))
+            .arg(clap::Arg::new("input").short('i').long("input").required(true))
+            .arg(clap::Arg::new("output").short('o').long("output").required(true))
+            .arg(clap::Arg::new("fasta").short('f').long("fasta"))
+            .arg(clap::Arg::new("genome").long("genome"))
+            .arg(clap::Arg::new("context").short('c').long("context").default_value("10"))
+            .arg(clap::Arg::new("motif").short('m').long("motif"))
+            .arg(clap::Arg::new("motif-offset").long("motif-offset"))
+            .arg(clap::Arg::new("coord-base").long("coord-base"))
+            .arg(clap::Arg::new("id-column").long("id-column"))
+            .arg(clap::Arg::new("pos-column").long("pos-column"))
+            .arg(clap::Arg::new("delimiter").long("delimiter"))
+            .arg(clap::Arg::new("input-format").long("input-format"))
+            .arg(clap::Arg::new("strict").long("strict").action(clap::ArgAction::SetTrue))
+            .arg(clap::Arg::new("dump-junctions").long("dump-junctions"))
+            .arg(clap::Arg::new("genomic").long("genomic").action(clap::ArgAction::SetTrue))
+            .arg(clap::Arg::new("zero-length-segments").long("zero-length-segments"))
+            .arg(clap::Arg::new("landmarks").long("landmarks").action(clap::ArgAction::Append))
+            .arg(clap::Arg::new("track").long("track").action(clap::ArgAction::Append))
+            .arg(clap::Arg::new("anchor").long("anchor"))
+            .arg(clap::Arg::new("interval-composition").long("interval-composition").action(clap::ArgAction::SetTrue))
+            .arg(clap::Arg::new("genomic-input").long("genomic-input").action(clap::ArgAction::SetTrue))
+            .arg(clap::Arg::new("isoforms").long("isoforms"))
+            .arg(clap::Arg::new("all-noncoding-metacoordinates").long("all-noncoding-metacoordinates").action(clap::ArgAction::SetTrue))
+    }
+
     #[test]
     fn test_calculate_cds_end() {
         assert_eq!(calculate_cds_end(100, 200), 300);
@@ -302,12 +947,117 @@ mod tests {
         assert_eq!(abs_cds_end, 50);
     }
 
+    #[test]
+    fn test_calculate_meta_coordinates_zero_length_segments() {
+        // no 5'UTR: the first CDS base is the CDS start
+        let (rel_pos, abs_cds_start, _) = calculate_meta_coordinates(0, 0, 200, 100);
+        assert_eq!(rel_pos, 1.0);
+        assert_eq!(abs_cds_start, 0);
+        assert_eq!(zero_length_segment(0, 0, 200, 100), None);
+
+        // no 3'UTR: sites past the CDS end (e.g. in an unannotated stop codon) collapse onto the CDS end
+        let (rel_pos, _, abs_cds_end) = calculate_meta_coordinates(301, 100, 200, 0);
+        assert_eq!(rel_pos, 2.0);
+        assert_eq!(abs_cds_end, 1);
+        assert_eq!(zero_length_segment(301, 100, 200, 0), Some(2));
+
+        // no CDS
+        let (rel_pos, _, _) = calculate_meta_coordinates(100, 100, 0, 0);
+        assert!(rel_pos.is_finite());
+        assert_eq!(zero_length_segment(100, 100, 0, 0), Some(2));
+        assert_eq!(zero_length_segment(99, 100, 0, 0), None);
+    }
+
+    #[test]
+    fn test_transcript_landmark_fields() {
+        // three exons of 100, 100 and 200 nt
+        let splice_sites: Vec<SpliceSite> = [100, 200].iter()
+            .map(|&tx_coord| SpliceSite { transcript_id: "T1".to_string(), tx_coord, ..Default::default() })
+            .collect();
+
+        // stop codon 100 nt upstream of the last junction
+        assert_eq!(transcript_landmark_fields(150, 400, &splice_sites, Some(100)), ["150", "249", "-50", "TRUE"]);
+        // stop codon in the last exon, and exactly 50 nt upstream of the last junction
+        assert_eq!(transcript_landmark_fields(250, 400, &splice_sites, Some(250)), ["250", "149", "50", "FALSE"]);
+        assert_eq!(transcript_landmark_fields(0, 400, &splice_sites, Some(150)), ["0", "399", "-200", "FALSE"]);
+        // non-coding transcript, and a site beyond the transcript end
+        assert_eq!(transcript_landmark_fields(399, 400, &splice_sites, None), ["399", "0", "199", "NA"]);
+        assert_eq!(transcript_landmark_fields(400, 400, &splice_sites, None), ["NA", "NA", "NA", "NA"]);
+        // single exon coding transcripts have no junction to trigger NMD
+        assert_eq!(transcript_landmark_fields(10, 400, &[], Some(300)), ["10", "389", "NA", "FALSE"]);
+    }
+
+    #[test]
+    fn test_site_region() {
+        let mut transcript = Transcript {
+            utr5_len: Some(10),
+            cds_len: Some(30),
+            utr3_len: Some(20),
+            transcript_length: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(site_region(&transcript, 9), "5UTR");
+        assert_eq!(site_region(&transcript, 10), "CDS");
+        assert_eq!(site_region(&transcript, 40), "3UTR");
+        assert_eq!(site_region(&transcript, 60), "NA");
+
+        transcript.cds_len = None;
+        assert_eq!(site_region(&transcript, 10), "non_coding");
+    }
+
+    #[test]
+    fn test_interval_composition_fields() {
+        assert_eq!(SiteAnchor::Start.position(10, 20), 10);
+        assert_eq!(SiteAnchor::End.position(10, 20), 19);
+        assert_eq!(SiteAnchor::Midpoint.position(10, 20), 14);
+        assert_eq!(SiteAnchor::Midpoint.position(10, 21), 15);
+        assert_eq!(SiteAnchor::Midpoint.position(10, 11), 10);
+
+        let mut transcript = Transcript {
+            utr5_len: Some(10),
+            cds_len: Some(30),
+            utr3_len: Some(20),
+            transcript_length: Some(60),
+            ..Default::default()
+        };
+        let splice_sites = vec![SpliceSite { transcript_id: "T1".to_string(), tx_coord: 35, ..Default::default() }];
+        assert_eq!(interval_composition_fields(5, 15, &transcript, Some(&splice_sites)), vec!["10", "5", "5", "0", "FALSE"]);
+        assert_eq!(interval_composition_fields(30, 45, &transcript, Some(&splice_sites)), vec!["15", "0", "10", "5", "TRUE"]);
+        // an interval starting at the junction lies within a single exon
+        assert_eq!(interval_composition_fields(35, 45, &transcript, Some(&splice_sites)), vec!["10", "0", "5", "5", "FALSE"]);
+        // bases beyond the transcript end are not counted
+        assert_eq!(interval_composition_fields(55, 70, &transcript, None), vec!["15", "0", "0", "5", "FALSE"]);
+
+        transcript.cds_len = None;
+        assert_eq!(interval_composition_fields(5, 15, &transcript, None), vec!["10", "NA", "NA", "NA", "FALSE"]);
+    }
+
+    #[test]
+    fn test_exon_position() {
+        let splice_sites: Vec<SpliceSite> = [10, 25].iter()
+            .map(|&tx_coord| SpliceSite { transcript_id: "T1".to_string(), tx_coord, ..Default::default() })
+            .collect();
+
+        let exon = exon_position(0, &splice_sites, 40).unwrap();
+        assert_eq!((exon.number, exon.length, exon.rel_pos, exon.is_first, exon.is_last), (1, 10, 0.0, true, false));
+        // the junction coordinate is the first base of the next exon
+        let exon = exon_position(10, &splice_sites, 40).unwrap();
+        assert_eq!((exon.number, exon.length, exon.is_first, exon.is_last), (2, 15, false, false));
+        let exon = exon_position(39, &splice_sites, 40).unwrap();
+        assert_eq!((exon.number, exon.length, exon.rel_pos, exon.is_last), (3, 15, 14.0 / 15.0, true));
+        assert_eq!(exon_position(40, &splice_sites, 40), None);
+
+        // single exon transcript
+        let exon = exon_position(5, &[], 20).unwrap();
+        assert!(exon.is_first && exon.is_last);
+    }
+
     #[test]
     fn test_splice_site_distances() {
         let splice_sites = vec![
-            SpliceSite { transcript_id: "test".to_string(), tx_coord: 50 },
-            SpliceSite { transcript_id: "test".to_string(), tx_coord: 100 },
-            SpliceSite { transcript_id: "test".to_string(), tx_coord: 150 },
+            SpliceSite { transcript_id: "test".to_string(), tx_coord: 50, ..Default::default() },
+            SpliceSite { transcript_id: "test".to_string(), tx_coord: 100, ..Default::default() },
+            SpliceSite { transcript_id: "test".to_string(), tx_coord: 150, ..Default::default() },
         ];
 
         // Test coordinate before all splice sites
@@ -336,10 +1086,7 @@ mod tests {
         let output_file = NamedTempFile::new().unwrap();
     
         // Create mock ArgMatches
-        let matches = clap::Command::new("test")
-            .arg(clap::Arg::new("gtf").short('g').long("gtf").required(true))
-            .arg(clap::Arg::new("input").short('i').long("input").required(true))
-            .arg(clap::Arg::new("output").short('o').long("output").required(true))
+        let matches = create_test_command()
             .get_matches_from(vec![
                 "test",
                 "-g", gtf_file.to_str().unwrap(),
@@ -357,12 +1104,12 @@ mod tests {
         assert_eq!(output_lines.len(), 2); // Header + 1 data line
     
         // Check header
-        let expected_header = "transcript\tstart\tend\tbase\tcoverage\tstrand\tN_valid_cov\tfraction_modified\tgene_id\tgene_name\ttranscript_biotype\ttx_len\tcds_start\tcds_end\ttx_end\ttranscript_metacoordinate\tabs_cds_start\tabs_cds_end\tup_junc_dist\tdown_junc_dist";
+        let expected_header = "transcript\tstart\tend\tbase\tcoverage\tstrand\tN_valid_cov\tfraction_modified\tgene_id\tgene_name\ttranscript_biotype\ttx_len\tcds_start\tcds_end\ttx_end\ttranscript_metacoordinate\tabs_cds_start\tabs_cds_end\tup_junc_dist\tdown_junc_dist\tregion\texon_number\texon_len\texon_rel_pos\tfirst_exon\tlast_exon\tframe\tcodon_number\tnoncoding_metacoordinate\tup_intron_len\tdown_intron_len\tup_exon_len\tdown_exon_len\tup_donor\tup_acceptor\tdown_donor\tdown_acceptor\ttss_dist\ttx_end_dist\tlast_junc_dist\tnmd_50nt";
         assert_eq!(output_lines[0], expected_header);
     
         // Check data line
         let data_fields: Vec<&str> = output_lines[1].split('\t').collect();
-        assert_eq!(data_fields.len(), 20); // Ensure we have the correct number of fields
+        assert_eq!(data_fields.len(), 41); // Ensure we have the correct number of fields
     
         // Check specific fields
         assert_eq!(data_fields[0], "ENST00000381989.4");
@@ -393,4 +1140,470 @@ mod tests {
     
         println!("Actual output: {}", output_lines[1]);
     }
-}
\ No newline at end of file
+
+    // write a single-line genome FASTA with its samtools faidx index
+    fn write_indexed_genome(genome_path: &str, genome: &str) {
+        let (header, sequence) = genome.trim_end().split_once('\n').unwrap();
+        let index = format!("{}\t{}\t{}\t{}\t{}\n", &header[1..], sequence.len(), header.len() + 1, sequence.len(), sequence.len() + 1);
+        std::fs::write(genome_path, genome).unwrap();
+        std::fs::write(format!("{}.fai", genome_path), index).unwrap();
+    }
+
+    // GTF and site files in a temporary directory, annotated through the mock command
+    struct AnnotateFixture {
+        dir: tempfile::TempDir,
+    }
+
+    impl AnnotateFixture {
+        fn new(gtf_data: &str, input_data: &str) -> Self {
+            let fixture = AnnotateFixture { dir: tempfile::tempdir().unwrap() };
+            fixture.write("test.gtf", gtf_data);
+            fixture.write("input.tsv", input_data);
+            fixture
+        }
+
+        fn path(&self, file_name: &str) -> String {
+            self.dir.path().join(file_name).to_str().unwrap().to_string()
+        }
+
+        // write a file next to the GTF and sites, returning its path
+        fn write(&self, file_name: &str, contents: &str) -> String {
+            let path = self.path(file_name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // run annotate with the extra arguments, returning the output file
+        fn run(&self, has_header: bool, args: &[&str]) -> Result<String, R2dError> {
+            let (gtf_path, input_path, output_path) = (self.path("test.gtf"), self.path("input.tsv"), self.path("output.tsv"));
+            let mut all_args = vec!["test", "-g", &gtf_path, "-i", &input_path, "-o", &output_path];
+            all_args.extend_from_slice(args);
+            run_annotate(&create_test_command().get_matches_from(all_args), has_header, false)?;
+            Ok(std::fs::read_to_string(&output_path).unwrap())
+        }
+
+        // annotate sites given with a header line
+        fn annotate(&self, args: &[&str]) -> AnnotatedTable {
+            AnnotatedTable::parse(&self.run(true, args).unwrap())
+        }
+    }
+
+    // annotate output, with its fields looked up by column name
+    struct AnnotatedTable {
+        header: Vec<String>,
+        rows: Vec<Vec<String>>,
+    }
+
+    impl AnnotatedTable {
+        fn parse(output: &str) -> Self {
+            let mut lines = output.lines().map(|line| line.split('\t').map(String::from).collect::<Vec<String>>());
+            let header = lines.next().unwrap();
+            AnnotatedTable { header, rows: lines.collect() }
+        }
+
+        // fields of the named columns, one vector per row
+        fn columns(&self, names: &[&str]) -> Vec<Vec<&str>> {
+            let indices: Vec<usize> = names.iter()
+                .map(|name| self.header.iter().position(|column| column == name).unwrap_or_else(|| panic!("missing column {}", name)))
+                .collect();
+            self.rows.iter().map(|row| indices.iter().map(|&i| row[i].as_str()).collect()).collect()
+        }
+
+        fn column(&self, name: &str) -> Vec<&str> {
+            self.columns(&[name]).into_iter().map(|fields| fields[0]).collect()
+        }
+    }
+
+    #[test]
+    fn test_run_annotate_sequence_context() {
+        // two exons (5-14 and 25-34) spliced into AAAAAAAGGA|CTAAAAAAAA
+        let genome = ">1\nCCCCAAAAAAAGGACCCCCCCCCCCTAAAAAAAACCCCCC\n";
+        let gtf_data = "1\ttest\ttranscript\t5\t34\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t25\t34\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        let input_data = "transcript\tstart\tend\nT1\t9\t10\nT1\t0\t1\n";
+
+        let fixture = AnnotateFixture::new(gtf_data, input_data);
+        let genome_path = fixture.path("genome.fa");
+        write_indexed_genome(&genome_path, genome);
+        let args = ["--genome", &genome_path, "-c", "2", "-m", "DRACH"];
+
+        let table = fixture.annotate(&args);
+        assert_eq!(table.header[3 + ANNOTATION_COLUMNS.len()..], ["codon", "amino_acid", "seq_context", "gc_content", "motif_match"]);
+        // the window around the last base of exon 1 crosses the junction, and the window at the transcript start is padded
+        assert_eq!(table.columns(&["seq_context", "gc_content", "motif_match"]), vec![
+            vec!["GGACT", "0.60000", "TRUE"],
+            vec!["NNAAA", "0.00000", "FALSE"],
+        ]);
+
+        assert!(fixture.run(true, &["--genome", &genome_path, "--context=-2"]).is_err());
+
+        // the genome must be indexed
+        std::fs::remove_file(format!("{}.fai", genome_path)).unwrap();
+        assert!(fixture.run(true, &args).is_err());
+    }
+
+    #[test]
+    fn test_run_annotate_one_based() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t25\t34\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        // the 1-based input sits on the same site as 0-based position 9, one nt upstream of the junction
+        let input_data = "transcript\tposition\nT1\t10\n";
+
+        let table = AnnotateFixture::new(gtf_data, input_data).annotate(&["--coord-base", "1"]);
+        assert_eq!(table.header.last().unwrap(), "coord_base");
+        assert_eq!(
+            table.columns(&["up_junc_dist", "down_junc_dist", "region", "exon_number", "exon_len", "exon_rel_pos", "first_exon", "last_exon"])[0],
+            ["NA", "1", "non_coding", "1", "10", "0.90000", "TRUE", "FALSE"]
+        );
+        assert_eq!(
+            table.columns(&["frame", "codon_number", "noncoding_metacoordinate", "up_intron_len", "down_intron_len", "up_exon_len", "down_exon_len"])[0],
+            ["NA", "NA", "0.45000", "NA", "10", "NA", "10"]
+        );
+        // donor and acceptor follow the 1-based input convention
+        assert_eq!(table.columns(&["up_donor", "up_acceptor", "down_donor", "down_acceptor"])[0], ["NA", "NA", "14", "25"]);
+        assert_eq!(
+            table.columns(&["tss_dist", "tx_end_dist", "last_junc_dist", "nmd_50nt", "coord_base"])[0],
+            ["9", "10", "-1", "NA", "1"]
+        );
+    }
+
+    #[test]
+    fn test_run_annotate_csv_columns_by_name() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        // m6anet style table, with the id and position columns selected by name
+        let input_data = "n_reads,transcript_position,transcript_id\n20,9,T1\n";
+
+        let output = AnnotateFixture::new(gtf_data, input_data)
+            .run(true, &["--delimiter", "comma", "--id-column", "transcript_id", "--pos-column", "transcript_position"])
+            .unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("n_reads\ttranscript_position\ttranscript_id\tgene_id\tgene_name"));
+        assert!(lines[1].starts_with("20\t9\tT1\tG1\tGENE1\tlncRNA\t"));
+    }
+
+    #[test]
+    fn test_run_annotate_m6anet_format() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        let input_data = "transcript_id,transcript_position,n_reads,probability_modified,kmer,mod_ratio\nT1,10,20,0.9,GGACT,0.4\n";
+
+        // the header is read without -H
+        let output = AnnotateFixture::new(gtf_data, input_data).run(false, &["--input-format", "m6anet"]).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("transcript\tstart\tend\tn_reads\tprobability_modified\tkmer\tmod_ratio\tgene_id\t"));
+        assert!(lines[1].starts_with("T1\t9\t10\t20\t0.9\tGGACT\t0.4\tG1\tGENE1\t"));
+    }
+
+    #[test]
+    fn test_run_annotate_malformed_rows() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        // a stray header, an NA position, a short row and a trailing empty line
+        let input_data = "transcript\tstart\nT1\t3\nT1\tNA\nT1\n\n";
+        let fixture = AnnotateFixture::new(gtf_data, input_data);
+
+        // lenient: only the valid row is annotated
+        let output = fixture.run(false, &[]).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.starts_with("T1\t3\tG1\t"));
+
+        // strict: the first malformed row is reported with its line number and value
+        let error = fixture.run(false, &["--strict"]).unwrap_err();
+        assert!(error.to_string().ends_with("line 1: invalid position 'start' in column 2"));
+    }
+
+    // /dev/full fails every write with ENOSPC
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_run_annotate_write_error() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t3\n");
+
+        let matches = create_test_command().get_matches_from(vec![
+            "test",
+            "-g", &fixture.path("test.gtf"),
+            "-i", &fixture.path("input.tsv"),
+            "-o", "/dev/full",
+        ]);
+        let error = run_annotate(&matches, true, false).unwrap_err();
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn test_run_annotate_dump_junctions() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t-\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t25\t34\t.\t-\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t45\t49\t.\t-\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t7\n");
+        let junctions_path = fixture.path("junctions.tsv");
+
+        let table = fixture.annotate(&["--dump-junctions", &junctions_path]);
+
+        // minus strand exons of 5 and 10 nt, read from the highest coordinate
+        let junctions = std::fs::read_to_string(&junctions_path).unwrap();
+        assert_eq!(junctions, "transcript_id\tjunction_number\ttx_coord\tdonor\tacceptor\tintron_len\nT1\t1\t5\t44\t33\t10\nT1\t2\t15\t24\t13\t10\n");
+
+        assert_eq!(
+            table.columns(&["up_junc_dist", "down_junc_dist", "region", "exon_number", "exon_len", "exon_rel_pos", "first_exon", "last_exon"])[0],
+            ["2", "8", "non_coding", "2", "10", "0.20000", "FALSE", "FALSE"]
+        );
+        assert_eq!(table.columns(&["frame", "codon_number", "noncoding_metacoordinate"])[0], ["NA", "NA", "0.28000"]);
+        // the middle exon is flanked by 10 nt introns, between a 5 nt upstream and a 10 nt downstream exon
+        assert_eq!(
+            table.columns(&["up_intron_len", "down_intron_len", "up_exon_len", "down_exon_len", "up_donor", "up_acceptor", "down_donor", "down_acceptor"])[0],
+            ["10", "10", "5", "10", "44", "33", "24", "13"]
+        );
+        assert_eq!(table.columns(&["tss_dist", "tx_end_dist", "last_junc_dist", "nmd_50nt"])[0], ["7", "17", "-8", "NA"]);
+    }
+
+    #[test]
+    fn test_run_annotate_keeps_input_order() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        // more rows than a single batch, alternating annotated and unknown transcripts, with a malformed row
+        let mut input_data = String::from("transcript\tstart\tname\n");
+        for i in 0..(BATCH_SIZE + 500) {
+            if i == 100 {
+                input_data.push_str("T1\tNA\n");
+            }
+            let transcript_id = if i % 2 == 0 { "T1" } else { "T2" };
+            input_data.push_str(&format!("{}\t{}\tsite{}\n", transcript_id, i % 10, i));
+        }
+
+        let table = AnnotateFixture::new(gtf_data, &input_data).annotate(&[]);
+        assert_eq!(table.rows.len(), BATCH_SIZE + 500);
+        for (i, fields) in table.columns(&["name", "gene_id"]).iter().enumerate() {
+            assert_eq!(fields[0], format!("site{}", i));
+            assert_eq!(fields[1], if i % 2 == 0 { "G1" } else { "NA" });
+        }
+    }
+
+    #[test]
+    fn test_run_annotate_genomic() {
+        let gtf_data = "1\ttest\texon\t5\t14\t.\t-\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t25\t34\t.\t-\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"lncRNA\";\n";
+        let input_data = "transcript\tstart\nT1\t2\nT1\t12\nT1\t25\nT2\t1\n";
+
+        let table = AnnotateFixture::new(gtf_data, input_data).annotate(&["--genomic"]);
+
+        // 0-based genomic positions on the minus strand, matching the liftover output
+        assert_eq!(table.columns(&["chromosome", "genomic_position", "strand"]), vec![
+            vec!["1", "31", "-"],
+            vec!["1", "11", "-"],
+            vec!["1", "NA", "-"],
+            vec!["NA", "NA", "NA"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_genomic_utr_and_coord_base() {
+        // UTR features overlap the exons and must not be counted as transcript sequence
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n\
+1\ttest\texon\t301\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n\
+1\ttest\tUTR\t101\t150\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n\
+1\ttest\tUTR\t351\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "");
+
+        // the same site, 0-based and 1-based: the genomic position keeps the convention of the input
+        for (site, coord_base, expected) in [("120", "0", "320"), ("121", "1", "321")] {
+            fixture.write("input.tsv", &format!("transcript\tstart\nT1\t{}\n", site));
+            let table = fixture.annotate(&["--coord-base", coord_base, "--genomic"]);
+            assert_eq!(table.column("genomic_position"), [expected]);
+        }
+    }
+
+    #[test]
+    fn test_run_annotate_codons() {
+        // 5 nt 5'UTR, a CDS of 7 codons (ATG GCA TGG AAA GGC TGC TGA) and a 4 nt 3'UTR
+        let genome = ">1\nCCCCCATGGCATGGAAAGGCTGCTGATTTT\n";
+        let gtf_data = "1\ttest\texon\t1\t30\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t1\t5\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tCDS\t6\t26\t.\t+\t0\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t27\t30\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"GENE1\"; transcript_biotype \"protein_coding\";\n";
+        let input_data = "transcript\tposition\nT1\t5\nT1\t10\nT1\t25\nT1\t2\nT1\t27\n";
+
+        let fixture = AnnotateFixture::new(gtf_data, input_data);
+        let genome_path = fixture.path("genome.fa");
+        write_indexed_genome(&genome_path, genome);
+
+        let table = fixture.annotate(&["--genome", &genome_path]);
+        assert_eq!(table.columns(&["region", "frame", "codon_number", "codon", "amino_acid"]), vec![
+            vec!["CDS", "0", "1", "ATG", "M"],
+            vec!["CDS", "2", "2", "GCA", "A"],
+            vec!["CDS", "2", "7", "TGA", "*"],
+            vec!["5UTR", "NA", "NA", "NA", "NA"],
+            vec!["3UTR", "NA", "NA", "NA", "NA"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_noncoding_metacoordinate() {
+        // coding transcript T1 (5 nt 5'UTR, 12 nt CDS, 3 nt 3'UTR) and lncRNA T2, both 20 nt long
+        let gtf_data = "1\ttest\texon\t1\t20\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t1\t5\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tCDS\t6\t17\t.\t+\t0\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t18\t20\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\texon\t101\t120\t.\t+\t.\tgene_id \"G2\"; transcript_id \"T2\"; transcript_biotype \"lncRNA\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t5\nT2\t5\nT2\t20\n");
+
+        for (args, expected) in [(vec![], ["NA", "0.25000", "NA"]), (vec!["--all-noncoding-metacoordinates"], ["0.25000", "0.25000", "NA"])] {
+            let table = fixture.annotate(&args);
+
+            // transcript_metacoordinate stays NA for the lncRNA, and the coding transcript keeps its own
+            assert_eq!(table.column("transcript_metacoordinate")[..2], ["1.00000", "NA"]);
+            assert_eq!(table.column("noncoding_metacoordinate"), expected);
+        }
+    }
+
+    #[test]
+    fn test_run_annotate_zero_length_segments() {
+        // 5 nt 5'UTR and a 12 nt CDS without an annotated 3'UTR, followed by the 3 nt stop codon
+        let gtf_data = "1\ttest\texon\t1\t20\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t1\t5\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tCDS\t6\t17\t.\t+\t0\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t10\nT1\t18\n");
+
+        for (policy, expected) in [("na", vec!["1.41667", "NA"]), ("clamp", vec!["1.41667", "2.00000"]), ("drop", vec!["1.41667"])] {
+            let table = fixture.annotate(&["--zero-length-segments", policy]);
+            assert_eq!(table.column("transcript_metacoordinate"), expected, "policy {}", policy);
+        }
+    }
+
+    #[test]
+    fn test_run_annotate_landmarks() {
+        let gtf_data = "1\ttest\texon\t1\t100\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t201\t300\t.\t+\t.\tgene_id \"G2\"; transcript_id \"T2\"; transcript_biotype \"lncRNA\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t15\nT1\t60\nT2\t15\n");
+        let seeds_path = fixture.write("mirna_seeds.bed", "T1\t20\t27\tmiR-1\nT1\t50\t57\tmiR-2\n");
+        let peaks_spec = format!("clip={}", fixture.write("peaks.bed", "T1.3\t0\t30\tpeak_1\n"));
+
+        let table = fixture.annotate(&["--landmarks", &seeds_path, "--landmarks", &peaks_spec]);
+        assert_eq!(table.columns(&["mirna_seeds_dist", "mirna_seeds_name", "clip_dist", "clip_name"]), vec![
+            vec!["-5", "miR-1", "0", "peak_1"],
+            vec!["4", "miR-2", "31", "peak_1"],
+            vec!["NA", "NA", "NA", "NA"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_duplicate_labels() {
+        let gtf_data = "1\ttest\texon\t1\t100\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t15\n");
+        std::fs::create_dir(fixture.path("a")).unwrap();
+        std::fs::create_dir(fixture.path("b")).unwrap();
+        let first = fixture.write("a/peaks.bed", "T1\t20\t27\tpeak_1\n");
+        let second = fixture.write("b/peaks.bed", "1\t10\t20\tpeak_2\n");
+
+        // the same file name would write two peaks_dist columns
+        let error = fixture.run(true, &["--landmarks", &first, "--track", &second]).unwrap_err();
+        assert_eq!(error.exit_code(), 5);
+        assert!(error.to_string().contains(&format!("are both labelled 'peaks', give one of them its own label, e.g. --track peaks_2={}", second)));
+
+        let renamed = format!("repeats={}", second);
+        let table = fixture.annotate(&["--landmarks", &first, "--track", &renamed]);
+        assert_eq!(table.columns(&["peaks_dist", "repeats_overlap"]), vec![vec!["-5", "TRUE"]]);
+    }
+
+    #[test]
+    fn test_run_annotate_tracks() {
+        let gtf_data = "1\ttest\texon\t1001\t1100\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t15\nT1\t60\n");
+        let landmarks_path = fixture.write("seeds.bed", "T1\t20\t27\tmiR-1\n");
+        let track_path = fixture.write("rmsk.bed", "1\t1010\t1020\tAluY\n");
+
+        let table = fixture.annotate(&["--landmarks", &landmarks_path, "--track", &track_path]);
+
+        // landmark columns come first, followed by the track columns of the 0-based genomic positions 1015 and 1060
+        assert_eq!(table.header[2 + ANNOTATION_COLUMNS.len()..], ["seeds_dist", "seeds_name", "rmsk_overlap", "rmsk_dist"]);
+        assert_eq!(table.columns(&["seeds_dist", "seeds_name", "rmsk_overlap", "rmsk_dist"]), vec![
+            vec!["-5", "miR-1", "TRUE", "0"],
+            vec!["34", "miR-1", "FALSE", "41"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_tracks_with_utr_features() {
+        // two exons with UTR features, so that the second site lies 10 nt into the second exon, at 0-based 1110
+        let gtf_data = "1\ttest\texon\t1001\t1050\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\texon\t1101\t1150\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t1001\t1010\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t1141\t1150\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n";
+        let fixture = AnnotateFixture::new(gtf_data, "transcript\tstart\nT1\t15\nT1\t60\n");
+        let track_path = fixture.write("rmsk.bed", "1\t1010\t1020\tAluY\n1\t1108\t1109\tL1\n");
+
+        let table = fixture.annotate(&["--track", &track_path, "--genomic"]);
+        assert_eq!(table.columns(&["genomic_position", "rmsk_overlap", "rmsk_dist"]), vec![
+            vec!["1015", "TRUE", "0"],
+            vec!["1110", "FALSE", "2"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_interval_anchor() {
+        let gtf_data = "1\ttest\texon\t1\t100\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\";\n\
+1\ttest\texon\t201\t300\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\";\n";
+        let input_data = "transcript\tstart\tend\tname\nT1\t0\t20\tpeak_1\nT1\t95\t110\tpeak_2\nT1\t50\t50\tempty\n";
+
+        let table = AnnotateFixture::new(gtf_data, input_data).annotate(&["--anchor", "midpoint", "--interval-composition"]);
+
+        // the interval without bases is skipped as malformed
+        assert_eq!(table.columns(&["name", "tss_dist", "interval_len", "utr5_bases", "cds_bases", "utr3_bases", "spans_junction"]), vec![
+            vec!["peak_1", "9", "20", "NA", "NA", "NA", "FALSE"],
+            vec!["peak_2", "102", "15", "NA", "NA", "NA", "TRUE"],
+        ]);
+    }
+
+    #[test]
+    fn test_run_annotate_genomic_input() {
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\"; tag \"basic\";\n\
+1\ttest\texon\t301\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"lncRNA\"; tag \"basic\";\n\
+1\ttest\texon\t151\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T2\"; transcript_biotype \"lncRNA\"; tag \"basic\"; tag \"Ensembl_canonical\";\n\
+1\ttest\texon\t101\t400\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T3\"; transcript_biotype \"lncRNA\";\n";
+        let input_data = "chrom\tstart\tend\tname\tscore\tstrand\n\
+1\t160\t161\tm6A_1\t0\t+\n\
+1\t250\t251\tm6A_2\t0\t.\n\
+1\t50\t51\tm6A_3\t0\t+\n";
+        let fixture = AnnotateFixture::new(gtf_data, input_data);
+
+        // input name, transcript_id, tx_coord and tss_dist of each output row
+        let run = |isoforms: &str| -> Vec<String> {
+            fixture.annotate(&["--genomic-input", "--isoforms", isoforms])
+                .columns(&["name", "transcript_id", "tx_coord", "tss_dist"]).iter()
+                .map(|fields| fields.join(" "))
+                .collect()
+        };
+
+        assert_eq!(run("all"), vec![
+            "m6A_1 T1 60 60",
+            "m6A_1 T2 10 10",
+            "m6A_2 T2 100 100",
+            "m6A_2 T3 149 149",
+            "m6A_3 NA NA NA",
+        ]);
+        assert_eq!(run("canonical"), vec![
+            "m6A_1 T2 10 10",
+            "m6A_2 T2 100 100",
+            "m6A_3 NA NA NA",
+        ]);
+        assert_eq!(run("best"), ["m6A_1 T2 10 10", "m6A_2 T2 100 100", "m6A_3 NA NA NA"]);
+    }
+
+    #[test]
+    fn test_run_annotate_genomic_input_round_trip() {
+        // the genomic position of each isoform hit is the input site, also on transcripts with UTR features
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\texon\t301\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t101\t150\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t351\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\texon\t121\t180\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\texon\t331\t390\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; transcript_biotype \"protein_coding\";\n\
+1\ttest\tUTR\t361\t390\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; transcript_biotype \"protein_coding\";\n";
+        let input_data = "chrom\tstart\tend\n1\t160\t161\n1\t340\t341\n";
+
+        let table = AnnotateFixture::new(gtf_data, input_data).annotate(&["--genomic-input", "--genomic"]);
+        assert_eq!(table.columns(&["start", "transcript_id", "tx_coord", "genomic_position"]), vec![
+            vec!["160", "T1", "60", "160"],
+            vec!["160", "T2", "79", "160"],
+            vec!["340", "T1", "140", "340"],
+            vec!["340", "T2", "49", "340"],
+        ]);
+    }
+}
@@ -0,0 +1,69 @@
+// reading of the BED files given to --landmarks and --track
+use std::io::BufRead;
+use crate::error::R2dError;
+
+// interval of a BED line in 0-based, half-open coordinates, with its name column when present
+#[derive(Debug, PartialEq)]
+pub struct BedRecord<'a> {
+    pub chrom: &'a str,
+    pub start: u64,
+    pub end: u64,
+    pub name: Option<&'a str>,
+}
+
+// pass each record of a BED file to the callback, skipping blank, comment, track and browser lines
+// malformed records are reported with the file name and line number
+pub fn for_each_bed_record<R, F>(file_path: &str, reader: R, mut callback: F) -> Result<(), R2dError>
+where
+    R: BufRead,
+    F: FnMut(BedRecord),
+{
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+        let first_word = line.split_whitespace().next().unwrap_or("");
+        if first_word.is_empty() || first_word.starts_with('#') || first_word == "track" || first_word == "browser" {
+            continue;
+        }
+
+        let invalid = |message: String| R2dError::InputParse(format!("{} line {}: {}", file_path, line_number + 1, message));
+        if fields.len() < 3 {
+            return Err(invalid(format!("missing column {} (found {} columns)", fields.len() + 1, fields.len())));
+        }
+        let parse = |field: &str| field.trim().parse::<u64>().map_err(|_| invalid(format!("invalid position '{}'", field)));
+        let (start, end) = (parse(fields[1])?, parse(fields[2])?);
+        if end <= start {
+            return Err(invalid(format!("end {} is not after its start {}", end, start)));
+        }
+
+        callback(BedRecord { chrom: fields[0], start, end, name: fields.get(3).map(|name| name.trim()) });
+    }
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bed_records() {
+        let bed = "track name=peaks\nbrowser position chr1:1-100\n# comment\n\nchr1\t10\t20\tpeak_1\r\nchr2\t5\t6\n";
+        let mut records = Vec::new();
+        for_each_bed_record("peaks.bed", bed.as_bytes(), |record| {
+            records.push((record.chrom.to_string(), record.start, record.end, record.name.map(String::from)));
+        }).unwrap();
+        assert_eq!(records, vec![
+            ("chr1".to_string(), 10, 20, Some("peak_1".to_string())),
+            ("chr2".to_string(), 5, 6, None),
+        ]);
+    }
+
+    #[test]
+    fn test_malformed_bed_records() {
+        let error = |bed: &str| for_each_bed_record("peaks.bed", bed.as_bytes(), |_| {}).unwrap_err().to_string();
+        assert_eq!(error("chr1\t10\n"), "Input parse error: peaks.bed line 1: missing column 3 (found 2 columns)");
+        assert_eq!(error("# header\nchr1\t10\tend\n"), "Input parse error: peaks.bed line 2: invalid position 'end'");
+        assert_eq!(error("chr1\t10\t10\n"), "Input parse error: peaks.bed line 1: end 10 is not after its start 10");
+    }
+}
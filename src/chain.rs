@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use crate::error::{R2dError, open_file};
+
+// header of a single UCSC chain
+// only the fields needed to convert target (old assembly) coordinates into query (new assembly) coordinates are kept
+#[derive(Debug, Clone)]
+pub struct ChainHeader {
+    pub score: f64,
+    pub q_name: String,
+    pub q_size: u64,
+    pub q_strand: char,
+}
+
+// ungapped block aligned between the two assemblies, in 0-based target coordinates
+#[derive(Debug, Clone, Copy)]
+struct ChainBlock {
+    chain: usize,
+    t_start: u64,
+    q_start: u64,
+}
+
+// position after conversion to the new assembly
+#[derive(Debug, PartialEq)]
+pub struct ChainMapping {
+    pub chromosome: String,
+    pub position: u64,
+    pub reverse: bool,
+}
+
+// reasons for which a position can not be converted with the chain file
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum UnmappedReason {
+    MissingChromosome,
+    NoChain,
+    DeletedInNew,
+}
+
+impl fmt::Display for UnmappedReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            UnmappedReason::MissingChromosome => "chromosome_not_in_chain_file",
+            UnmappedReason::NoChain => "no_chain_at_position",
+            UnmappedReason::DeletedInNew => "deleted_in_new",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+// chain file indexed by target chromosome
+// blocks are used to convert positions, chain spans are used to tell deletions apart from unaligned regions
+pub struct ChainMap {
+    chains: Vec<ChainHeader>,
+    blocks: HashMap<String, ArrayBackedIntervalTree<u64, ChainBlock>>,
+    spans: HashMap<String, ArrayBackedIntervalTree<u64, usize>>,
+}
+
+impl ChainMap {
+    pub fn from_file(file_path: &str) -> Result<Self, R2dError> {
+        let reader = BufReader::new(open_file(file_path)?);
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, R2dError> {
+        let mut chains = Vec::new();
+        let mut blocks: HashMap<String, ArrayBackedIntervalTree<u64, ChainBlock>> = HashMap::new();
+        let mut spans: HashMap<String, ArrayBackedIntervalTree<u64, usize>> = HashMap::new();
+
+        // target chromosome and current target / query offsets of the chain being read
+        let mut current: Option<(String, u64, u64)> = None;
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+
+            let parse = |field: &str| -> Result<u64, R2dError> {
+                field.parse::<u64>().map_err(|_| R2dError::AnnotationParse(
+                    format!("Invalid value '{}' on line {} of chain file", field, line_number + 1)
+                ))
+            };
+
+            if fields[0] == "chain" {
+                if fields.len() < 12 {
+                    return Err(R2dError::AnnotationParse(format!("Malformed chain header on line {} of chain file", line_number + 1)));
+                }
+                let t_name = fields[2].to_string();
+                let (t_start, t_end) = (parse(fields[5])?, parse(fields[6])?);
+                let q_start = parse(fields[10])?;
+
+                chains.push(ChainHeader {
+                    score: fields[1].parse().map_err(|_| R2dError::AnnotationParse(
+                        format!("Invalid chain score '{}' on line {} of chain file", fields[1], line_number + 1)
+                    ))?,
+                    q_name: fields[7].to_string(),
+                    q_size: parse(fields[8])?,
+                    q_strand: fields[9].chars().next().unwrap_or('+'),
+                });
+                if t_start < t_end {
+                    spans.entry(t_name.clone()).or_default().insert(t_start..t_end, chains.len() - 1);
+                }
+                current = Some((t_name, t_start, q_start));
+                continue;
+            }
+
+            // alignment data line: size [dt dq]
+            let (t_name, t_pos, q_pos) = current.as_mut()
+                .ok_or_else(|| R2dError::AnnotationParse(
+                    format!("Alignment data before chain header on line {} of chain file", line_number + 1)
+                ))?;
+            let size = parse(fields[0])?;
+            if size > 0 {
+                blocks.entry(t_name.clone()).or_default().insert(*t_pos..*t_pos + size, ChainBlock {
+                    chain: chains.len() - 1,
+                    t_start: *t_pos,
+                    q_start: *q_pos,
+                });
+            }
+            *t_pos += size;
+            *q_pos += size;
+
+            if fields.len() >= 3 {
+                *t_pos += parse(fields[1])?;
+                *q_pos += parse(fields[2])?;
+            } else {
+                current = None;
+            }
+        }
+
+        blocks.values_mut().for_each(|tree| tree.index());
+        spans.values_mut().for_each(|tree| tree.index());
+
+        Ok(ChainMap { chains, blocks, spans })
+    }
+
+    // convert a 0-based position; when several chains cover the position, the highest scoring chain is used
+    pub fn map_position(&self, chromosome: &str, position: u64) -> Result<ChainMapping, UnmappedReason> {
+        let tree = self.blocks.get(chromosome).ok_or(UnmappedReason::MissingChromosome)?;
+
+        let best = tree.find(position..position + 1)
+            .into_iter()
+            .map(|entry| *entry.data())
+            .max_by(|a, b| self.chains[a.chain].score.total_cmp(&self.chains[b.chain].score));
+
+        match best {
+            Some(block) => {
+                let chain = &self.chains[block.chain];
+                let q_position = block.q_start + (position - block.t_start);
+                let reverse = chain.q_strand == '-';
+                Ok(ChainMapping {
+                    chromosome: chain.q_name.clone(),
+                    // query coordinates of reverse strand chains count from the end of the query chromosome
+                    position: if reverse { chain.q_size - 1 - q_position } else { q_position },
+                    reverse,
+                })
+            }
+            None => {
+                let in_chain = self.spans.get(chromosome)
+                    .is_some_and(|spans| !spans.find(position..position + 1).is_empty());
+                if in_chain {
+                    Err(UnmappedReason::DeletedInNew)
+                } else {
+                    Err(UnmappedReason::NoChain)
+                }
+            }
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn create_test_chain_map() -> ChainMap {
+        let chain_data = "\
+chain 1000 chr1 1000 + 100 270 chr1 2000 + 1100 1260 1
+50 20 10
+100
+
+chain 500 chr2 500 + 0 100 chrB 1000 - 0 100 2
+100
+
+";
+        ChainMap::from_reader(Cursor::new(chain_data)).unwrap()
+    }
+
+    #[test]
+    fn test_map_position_forward() {
+        let chain_map = create_test_chain_map();
+        let mapping = chain_map.map_position("chr1", 110).unwrap();
+        assert_eq!(mapping, ChainMapping { chromosome: "chr1".to_string(), position: 1110, reverse: false });
+
+        // second block starts at target 170 and query 1160
+        let mapping = chain_map.map_position("chr1", 175).unwrap();
+        assert_eq!(mapping.position, 1165);
+    }
+
+    #[test]
+    fn test_map_position_reverse() {
+        let chain_map = create_test_chain_map();
+        let mapping = chain_map.map_position("chr2", 10).unwrap();
+        assert_eq!(mapping, ChainMapping { chromosome: "chrB".to_string(), position: 989, reverse: true });
+    }
+
+    #[test]
+    fn test_map_position_unmapped() {
+        let chain_map = create_test_chain_map();
+        assert_eq!(chain_map.map_position("chr1", 160), Err(UnmappedReason::DeletedInNew));
+        assert_eq!(chain_map.map_position("chr1", 50), Err(UnmappedReason::NoChain));
+        assert_eq!(chain_map.map_position("chr3", 50), Err(UnmappedReason::MissingChromosome));
+    }
+
+    #[test]
+    fn test_malformed_chain() {
+        let result = ChainMap::from_reader(Cursor::new("chain 1000 chr1 1000 + 100\n"));
+        assert!(result.is_err());
+        let result = ChainMap::from_reader(Cursor::new("50 20 10\n"));
+        assert!(result.is_err());
+        let error = ChainMap::from_reader(Cursor::new("chain high chr1 1000 + 100 200 chr1 1000 + 100 200 1\n100\n")).err().unwrap();
+        assert_eq!(error.to_string(), "Annotation parse error: Invalid chain score 'high' on line 1 of chain file");
+    }
+}
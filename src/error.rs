@@ -0,0 +1,96 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+
+// errors reported by the r2d subcommands, grouped into classes with their own exit codes
+#[derive(Debug)]
+pub enum R2dError {
+    // reading or writing a file or stream
+    Io(io::Error),
+    // malformed GTF, chain, FASTA or chromosome sizes file
+    AnnotationParse(String),
+    // malformed input sites, alignments or option values
+    InputParse(String),
+    // input that could not be placed on the annotated transcripts or the genome
+    Mapping(String),
+    // failure of the R script behind a plot command
+    Plot(String),
+}
+
+impl R2dError {
+    // exit codes start at 3, as clap exits with 2 on usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            R2dError::Io(_) => 3,
+            R2dError::AnnotationParse(_) => 4,
+            R2dError::InputParse(_) => 5,
+            R2dError::Mapping(_) => 6,
+            R2dError::Plot(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for R2dError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            R2dError::Io(e) => write!(f, "I/O error: {}", e),
+            R2dError::AnnotationParse(message) => write!(f, "Annotation parse error: {}", message),
+            R2dError::InputParse(message) => write!(f, "Input parse error: {}", message),
+            R2dError::Mapping(message) => write!(f, "Mapping error: {}", message),
+            R2dError::Plot(message) => write!(f, "Plot error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for R2dError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            R2dError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for R2dError {
+    fn from(e: io::Error) -> Self {
+        R2dError::Io(e)
+    }
+}
+
+// open a file for reading, naming it in the error
+pub fn open_file(file_path: &str) -> Result<File, R2dError> {
+    File::open(file_path).map_err(|e| R2dError::Io(io::Error::new(e.kind(), format!("cannot open {}: {}", file_path, e))))
+}
+
+// create a file for writing, naming it in the error
+pub fn create_file(file_path: &str) -> Result<File, R2dError> {
+    File::create(file_path).map_err(|e| R2dError::Io(io::Error::new(e.kind(), format!("cannot create {}: {}", file_path, e))))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let errors = [
+            R2dError::Io(io::Error::other("closed")),
+            R2dError::AnnotationParse("gtf".to_string()),
+            R2dError::InputParse("sites".to_string()),
+            R2dError::Mapping("sites".to_string()),
+            R2dError::Plot("Rscript".to_string()),
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        assert!(codes.iter().all(|&code| code > 2));
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn test_open_file_names_path() {
+        let error = open_file("/nonexistent/sites.bed").unwrap_err();
+        assert_eq!(error.exit_code(), 3);
+        assert!(error.to_string().contains("/nonexistent/sites.bed"));
+    }
+}
@@ -0,0 +1,583 @@
+// parsing of transcriptomic site inputs shared by liftover and annotate
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader};
+use log::warn;
+use crate::error::{R2dError, open_file};
+
+// rows read and processed together in each parallel batch, which bounds the memory used on large inputs
+pub const BATCH_SIZE: usize = 20000;
+
+// path of the site input, where "-" or an omitted --input stands for stdin
+pub fn input_file_from_matches(matches: &clap::ArgMatches) -> String {
+    matches.get_one::<String>("input").map_or("-", |file_path| file_path.as_str()).to_string()
+}
+
+// name of the input used in messages
+pub fn input_name(input_file: &str) -> &str {
+    if input_file == "-" { "stdin" } else { input_file }
+}
+
+// open the site input, reading stdin so that r2d can sit in a pipeline
+pub fn open_input(input_file: &str) -> Result<Box<dyn BufRead>, R2dError> {
+    if input_file == "-" {
+        Ok(Box::new(BufReader::with_capacity(512 * 1024, io::stdin())))
+    } else {
+        Ok(Box::new(BufReader::with_capacity(512 * 1024, open_file(input_file)?)))
+    }
+}
+
+// label and path of a file given as NAME=FILE, or as FILE labelled with its name without extensions
+pub fn named_file(spec: &str) -> (String, String) {
+    if let Some((name, file_path)) = spec.split_once('=') {
+        if !name.is_empty() && !name.contains('/') {
+            return (name.to_string(), file_path.to_string());
+        }
+    }
+    let file_name = std::path::Path::new(spec).file_name().map_or(spec.to_string(), |name| name.to_string_lossy().into_owned());
+    let label = file_name.split('.').next().filter(|label| !label.is_empty()).unwrap_or(&file_name);
+    (label.to_string(), spec.to_string())
+}
+
+// the labels of the files given to --landmarks and --track name output columns, so each must be used once
+pub fn check_unique_labels(matches: &clap::ArgMatches, options: &[&str]) -> Result<(), R2dError> {
+    let mut labels: HashMap<String, String> = HashMap::new();
+    for option in options {
+        for spec in matches.get_many::<String>(option).unwrap_or_default() {
+            let (label, file_path) = named_file(spec);
+            if let Some(previous) = labels.insert(label.clone(), file_path.clone()) {
+                return Err(R2dError::InputParse(format!(
+                    "{} and {} are both labelled '{}', give one of them its own label, e.g. --{} {}_2={}",
+                    previous, file_path, label, option, label, file_path
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// read the next batch of input rows with their line numbers, returning false once the input is exhausted
+pub fn read_batch<I>(lines: &mut I, batch: &mut Vec<(usize, String)>) -> Result<bool, R2dError>
+where
+    I: Iterator<Item = (usize, io::Result<String>)>,
+{
+    batch.clear();
+    for (line_number, line) in lines.by_ref().take(BATCH_SIZE) {
+        batch.push((line_number, line?));
+    }
+    Ok(!batch.is_empty())
+}
+
+// read the coordinate convention of the input position column
+// None when the option was not given, in which case the input is treated as 0-based
+pub fn coord_base_from_matches(matches: &clap::ArgMatches) -> Result<Option<u64>, R2dError> {
+    match matches.get_one::<String>("coord-base").map(|base| base.as_str()) {
+        None => Ok(None),
+        Some("0") => Ok(Some(0)),
+        Some("1") => Ok(Some(1)),
+        Some(other) => Err(R2dError::InputParse(format!("Invalid coordinate base '{}', expected 0 or 1", other))),
+    }
+}
+
+// convert the position column of an input site into a 0-based transcript coordinate
+pub fn parse_position(field: &str, coord_base: u64) -> Option<u64> {
+    field.trim().parse::<u64>().ok()?.checked_sub(coord_base)
+}
+
+// site tables written by modification callers that can be read without conversion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Bed,
+    Cheui,
+    M6anet,
+    Xpore,
+    Modkit,
+    Epinano,
+}
+
+// column layout of a caller's site table
+struct FormatSpec {
+    delimiter: char,
+    id_names: &'static [&'static str],
+    position_names: &'static [&'static str],
+    coord_base: u64,
+    // added to the reported position to reach the modified base, e.g. the centre of a k-mer
+    offset: u64,
+    // column names of tables written without a header
+    columns: Option<&'static [&'static str]>,
+    // columns made redundant by the normalised start and end
+    dropped: &'static [&'static str],
+}
+
+const BEDMETHYL_COLUMNS: &[&str] = &[
+    "chrom", "start", "end", "mod_code", "score", "strand", "start_thick", "end_thick", "color",
+    "n_valid_cov", "fraction_modified", "n_mod", "n_canonical", "n_other_mod", "n_delete", "n_fail", "n_diff", "n_nocall",
+];
+
+impl InputFormat {
+    pub fn from_name(name: &str) -> Result<Self, R2dError> {
+        match name {
+            "bed" => Ok(InputFormat::Bed),
+            "cheui" => Ok(InputFormat::Cheui),
+            "m6anet" => Ok(InputFormat::M6anet),
+            "xpore" => Ok(InputFormat::Xpore),
+            "modkit" => Ok(InputFormat::Modkit),
+            "epinano" => Ok(InputFormat::Epinano),
+            other => Err(R2dError::InputParse(format!("Unknown input format '{}', expected bed, cheui, m6anet, xpore, modkit or epinano", other))),
+        }
+    }
+
+    // whether the input starts with a header row; None when this is given by the -H flag
+    pub fn has_header(&self) -> Option<bool> {
+        match self {
+            InputFormat::Bed => None,
+            InputFormat::Modkit => Some(false),
+            _ => Some(true),
+        }
+    }
+
+    fn spec(&self) -> Option<FormatSpec> {
+        match self {
+            InputFormat::Bed => None,
+            // CHEUI model II reports the 0-based start of the 9-mer centred on the site
+            InputFormat::Cheui => Some(FormatSpec {
+                delimiter: '\t', id_names: &["contig"], position_names: &["position"],
+                coord_base: 0, offset: 4, columns: None, dropped: &[],
+            }),
+            InputFormat::M6anet => Some(FormatSpec {
+                delimiter: ',', id_names: &["transcript_id"], position_names: &["transcript_position"],
+                coord_base: 1, offset: 0, columns: None, dropped: &[],
+            }),
+            InputFormat::Xpore => Some(FormatSpec {
+                delimiter: ',', id_names: &["id"], position_names: &["position"],
+                coord_base: 1, offset: 0, columns: None, dropped: &[],
+            }),
+            InputFormat::Modkit => Some(FormatSpec {
+                delimiter: '\t', id_names: &["chrom"], position_names: &["start"],
+                coord_base: 0, offset: 0, columns: Some(BEDMETHYL_COLUMNS), dropped: &["end"],
+            }),
+            // per-site tables of EpiNano-Error
+            InputFormat::Epinano => Some(FormatSpec {
+                delimiter: ',', id_names: &["#Ref", "Ref"], position_names: &["pos"],
+                coord_base: 1, offset: 0, columns: None, dropped: &[],
+            }),
+        }
+    }
+}
+
+pub fn input_format_from_matches(matches: &clap::ArgMatches) -> Result<InputFormat, R2dError> {
+    match matches.get_one::<String>("input-format") {
+        Some(name) => InputFormat::from_name(name),
+        None => Ok(InputFormat::Bed),
+    }
+}
+
+// layout of an input site table: the delimiter, the 0-based indices of the transcript id
+// and position columns, and the coordinate convention of the position column
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteLayout {
+    pub id_column: usize,
+    pub position_column: usize,
+    pub delimiter: char,
+    pub coord_base: u64,
+    pub offset: u64,
+    // for caller formats, the columns kept after the normalised transcript, start and end
+    // None when the input columns are written out unchanged
+    pub kept_columns: Option<Vec<usize>>,
+    // header names of the kept columns, for caller formats without a header row
+    pub column_names: Option<Vec<String>>,
+    // column holding the exclusive end of interval sites (e.g. peaks or windows), None for single-base sites
+    pub end_column: Option<usize>,
+}
+
+impl Default for SiteLayout {
+    fn default() -> Self {
+        Self {
+            id_column: 0,
+            position_column: 1,
+            delimiter: '\t',
+            coord_base: 0,
+            offset: 0,
+            kept_columns: None,
+            column_names: None,
+            end_column: None,
+        }
+    }
+}
+
+// common record of an input site: transcript, 0-based transcript coordinate and the
+// tab separated columns carried through to the output
+#[derive(Debug, PartialEq)]
+pub struct SiteRecord<'a> {
+    pub transcript_id: &'a str,
+    pub position: u64,
+    pub columns: String,
+}
+
+impl SiteLayout {
+    pub fn split<'a>(&self, line: &'a str) -> Vec<&'a str> {
+        line.split(self.delimiter).collect()
+    }
+
+    // transcript id and 0-based transcript coordinate of a site
+    pub fn site<'a>(&self, fields: &[&'a str]) -> Option<(&'a str, u64)> {
+        self.validate_site(fields).ok()
+    }
+
+    // as site, describing the offending column when the row is malformed
+    pub fn validate_site<'a>(&self, fields: &[&'a str]) -> Result<(&'a str, u64), String> {
+        let column = |index: usize| fields.get(index).ok_or_else(|| format!(
+            "missing column {} (found {} columns)", index + 1, fields.len()
+        ));
+        let transcript_id = column(self.id_column)?.trim();
+        if transcript_id.is_empty() {
+            return Err(format!("empty transcript id in column {}", self.id_column + 1));
+        }
+        let field = column(self.position_column)?;
+        let position = parse_position(field, self.coord_base).ok_or_else(|| match field.trim().parse::<u64>() {
+            Ok(_) => format!("position '{}' in column {} is below the coordinate base {}", field.trim(), self.position_column + 1, self.coord_base),
+            Err(_) => format!("invalid position '{}' in column {}", field.trim(), self.position_column + 1),
+        })?;
+        Ok((transcript_id, position + self.offset))
+    }
+
+    // 0-based, half-open interval of a site starting at the validated position, a single base without an end column
+    // the end is exclusive in 0-based inputs and inclusive in 1-based inputs, i.e. the same 0-based value in both
+    pub fn validate_interval(&self, fields: &[&str], start: u64) -> Result<(u64, u64), String> {
+        let Some(end_column) = self.end_column else {
+            return Ok((start, start + 1));
+        };
+        let field = fields.get(end_column).ok_or_else(|| format!(
+            "missing column {} (found {} columns)", end_column + 1, fields.len()
+        ))?;
+        match field.trim().parse::<u64>() {
+            Ok(end) if end > start => Ok((start, end)),
+            Ok(_) => Err(format!("end '{}' in column {} is not after the start", field.trim(), end_column + 1)),
+            Err(_) => Err(format!("invalid end '{}' in column {}", field.trim(), end_column + 1)),
+        }
+    }
+
+    pub fn record<'a>(&self, fields: &[&'a str]) -> Option<SiteRecord<'a>> {
+        self.validate_record(fields).ok()
+    }
+
+    pub fn validate_record<'a>(&self, fields: &[&'a str]) -> Result<SiteRecord<'a>, String> {
+        let (transcript_id, position) = self.validate_site(fields)?;
+        let columns = match &self.kept_columns {
+            None => fields.join("\t"),
+            Some(kept) => {
+                let mut columns = format!("{}\t{}\t{}", transcript_id, position, position + 1);
+                for &column in kept {
+                    columns.push('\t');
+                    columns.push_str(fields.get(column).copied().unwrap_or(""));
+                }
+                columns
+            }
+        };
+        Ok(SiteRecord { transcript_id, position, columns })
+    }
+
+    // tab separated header of the columns carried through to the output, None when there is none
+    pub fn output_header(&self, header: Option<&str>) -> Option<String> {
+        let header_fields: Vec<&str> = header.map(|line| self.split(line.trim())).unwrap_or_default();
+        match &self.kept_columns {
+            None => header.map(|_| header_fields.join("\t")),
+            Some(kept) => {
+                let mut output_header = "transcript\tstart\tend".to_string();
+                for (i, &column) in kept.iter().enumerate() {
+                    let name = match &self.column_names {
+                        Some(names) => names[i].as_str(),
+                        None => header_fields.get(column).copied().unwrap_or(""),
+                    };
+                    output_header.push('\t');
+                    output_header.push_str(name);
+                }
+                Some(output_header)
+            }
+        }
+    }
+}
+
+fn parse_delimiter(value: &str) -> Result<char, R2dError> {
+    match value {
+        "tab" | "\\t" | "\t" => Ok('\t'),
+        "comma" | "," => Ok(','),
+        "space" | " " => Ok(' '),
+        other if other.chars().count() == 1 => Ok(other.chars().next().unwrap()),
+        other => Err(R2dError::InputParse(format!("Invalid delimiter '{}', expected a single character, 'tab', 'comma' or 'space'", other))),
+    }
+}
+
+// resolve a column given either as a 1-based index or as a header name
+fn resolve_column(value: &str, header_fields: Option<&[&str]>, option: &str) -> Result<usize, R2dError> {
+    if let Ok(index) = value.parse::<usize>() {
+        return index.checked_sub(1).ok_or_else(|| R2dError::InputParse(format!("Column indices for --{} start at 1", option)));
+    }
+    let header_fields = header_fields.ok_or_else(|| R2dError::InputParse(
+        format!("Selecting --{} by name ('{}') requires an input header (-H)", option, value)
+    ))?;
+    header_fields.iter()
+        .position(|name| name.trim() == value)
+        .ok_or_else(|| R2dError::InputParse(format!("Column '{}' given to --{} is not in the input header", value, option)))
+}
+
+// layout of a caller's site table, with the columns found by name in its header
+fn format_layout(format: InputFormat, spec: FormatSpec, header: Option<&str>, coord_base: Option<u64>) -> Result<SiteLayout, R2dError> {
+    let header_fields: Vec<&str> = match (spec.columns, header) {
+        (Some(columns), _) => columns.to_vec(),
+        (None, Some(line)) => line.trim_end_matches(['\n', '\r']).split(spec.delimiter).map(|name| name.trim()).collect(),
+        (None, None) => return Err(R2dError::InputParse(format!("Missing header row in {:?} input", format))),
+    };
+    let find = |names: &[&str]| {
+        header_fields.iter().position(|field| names.contains(field))
+            .ok_or_else(|| R2dError::InputParse(format!("Input does not look like {:?} output: no '{}' column in the header", format, names[0])))
+    };
+    let id_column = find(spec.id_names)?;
+    let position_column = find(spec.position_names)?;
+
+    let kept: Vec<usize> = (0..header_fields.len())
+        .filter(|&i| i != id_column && i != position_column && !spec.dropped.contains(&header_fields[i]))
+        .collect();
+    let column_names = spec.columns.map(|_| kept.iter().map(|&i| header_fields[i].to_string()).collect());
+
+    Ok(SiteLayout {
+        id_column,
+        position_column,
+        delimiter: spec.delimiter,
+        coord_base: coord_base.unwrap_or(spec.coord_base),
+        offset: spec.offset,
+        kept_columns: Some(kept),
+        column_names,
+        end_column: None,
+    })
+}
+
+// read the input layout options, using the header line to resolve columns selected by name
+pub fn site_layout_from_matches(matches: &clap::ArgMatches, header: Option<&str>) -> Result<SiteLayout, R2dError> {
+    let coord_base = coord_base_from_matches(matches)?;
+    let format = input_format_from_matches(matches)?;
+    if let Some(spec) = format.spec() {
+        return format_layout(format, spec, header, coord_base);
+    }
+
+    let delimiter = match matches.get_one::<String>("delimiter") {
+        Some(value) => parse_delimiter(value)?,
+        None => '\t',
+    };
+    let header_fields: Option<Vec<&str>> = header.map(|line| line.trim_end_matches(['\n', '\r']).split(delimiter).collect());
+
+    let id_column = match matches.get_one::<String>("id-column") {
+        Some(value) => resolve_column(value, header_fields.as_deref(), "id-column")?,
+        None => 0,
+    };
+    let position_column = match matches.get_one::<String>("pos-column") {
+        Some(value) => resolve_column(value, header_fields.as_deref(), "pos-column")?,
+        None => 1,
+    };
+
+    Ok(SiteLayout {
+        id_column,
+        position_column,
+        delimiter,
+        coord_base: coord_base.unwrap_or(0),
+        ..Default::default()
+    })
+}
+
+// handling of malformed input rows: with --strict the first one is an error,
+// otherwise they are skipped and counted
+pub struct RowValidator {
+    file: String,
+    strict: bool,
+    skipped: usize,
+    first_error: Option<String>,
+}
+
+impl RowValidator {
+    pub fn new(file: &str, strict: bool) -> Self {
+        Self { file: file.to_string(), strict, skipped: 0, first_error: None }
+    }
+
+    pub fn from_matches(matches: &clap::ArgMatches, file: &str) -> Self {
+        Self::new(file, matches.get_flag("strict"))
+    }
+
+    // pass a valid row through, and skip or fail on an invalid one
+    pub fn check<T>(&mut self, line_number: usize, result: Result<T, String>) -> Result<Option<T>, R2dError> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(message) => {
+                let message = format!("{} line {}: {}", self.file, line_number, message);
+                if self.strict {
+                    return Err(R2dError::InputParse(message));
+                }
+                self.skipped += 1;
+                self.first_error.get_or_insert(message);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    // summary of the skipped rows
+    pub fn report(&self) {
+        if let Some(first_error) = &self.first_error {
+            warn!(
+                "Skipped {} malformed rows in {} (first: {}). Use --strict to stop at the first malformed row.",
+                self.skipped, self.file, first_error
+            );
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_column() {
+        let header = ["transcript_id", "transcript_position", "n_reads"];
+        assert_eq!(resolve_column("3", None, "id-column").unwrap(), 2);
+        assert_eq!(resolve_column("transcript_position", Some(&header), "pos-column").unwrap(), 1);
+        assert!(resolve_column("0", None, "id-column").is_err());
+        assert!(resolve_column("transcript_id", None, "id-column").is_err());
+        assert!(resolve_column("probability", Some(&header), "id-column").is_err());
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter("comma").unwrap(), ',');
+        assert_eq!(parse_delimiter("\\t").unwrap(), '\t');
+        assert_eq!(parse_delimiter(";").unwrap(), ';');
+        assert!(parse_delimiter("::").is_err());
+    }
+
+    #[test]
+    fn test_site_layout() {
+        let layout = SiteLayout { id_column: 2, position_column: 0, delimiter: ',', coord_base: 1, ..Default::default() };
+        let fields = layout.split("11,0.9,ENST00000381989.4");
+        assert_eq!(layout.site(&fields), Some(("ENST00000381989.4", 10)));
+        assert_eq!(layout.site(&["11", "0.9"]), None);
+    }
+
+    #[test]
+    fn test_cheui_layout() {
+        let header = "contig\tposition\tsite\tcoverage\tstoichiometry\tprobability\n";
+        let layout = format_layout(InputFormat::Cheui, InputFormat::Cheui.spec().unwrap(), Some(header), None).unwrap();
+        assert_eq!(layout.output_header(Some(header)).unwrap(), "transcript\tstart\tend\tsite\tcoverage\tstoichiometry\tprobability");
+
+        let fields = layout.split("ENST00000381989.4\t100\tAAGGACTAA\t30\t0.4\t0.99");
+        let record = layout.record(&fields).unwrap();
+        assert_eq!((record.transcript_id, record.position), ("ENST00000381989.4", 104));
+        assert_eq!(record.columns, "ENST00000381989.4\t104\t105\tAAGGACTAA\t30\t0.4\t0.99");
+    }
+
+    #[test]
+    fn test_modkit_layout() {
+        let layout = format_layout(InputFormat::Modkit, InputFormat::Modkit.spec().unwrap(), None, None).unwrap();
+        let header = layout.output_header(None).unwrap();
+        assert!(header.starts_with("transcript\tstart\tend\tmod_code\tscore\tstrand\t"));
+
+        let fields = layout.split("tx1\t10\t11\ta\t20\t+\t10\t11\t255,0,0\t20\t50.00\t10\t10\t0\t0\t0\t0\t0");
+        let record = layout.record(&fields).unwrap();
+        assert_eq!(record.position, 10);
+        assert!(record.columns.starts_with("tx1\t10\t11\ta\t20\t+\t10\t11\t"));
+        assert_eq!(header.split('\t').count(), record.columns.split('\t').count());
+    }
+
+    #[test]
+    fn test_format_layout_missing_column() {
+        let header = "transcript_id,position,n_reads";
+        assert!(format_layout(InputFormat::M6anet, InputFormat::M6anet.spec().unwrap(), Some(header), None).is_err());
+        assert!(format_layout(InputFormat::Xpore, InputFormat::Xpore.spec().unwrap(), None, None).is_err());
+    }
+
+    #[test]
+    fn test_validate_site() {
+        let layout = SiteLayout { coord_base: 1, ..Default::default() };
+        assert_eq!(layout.validate_site(&["tx1", "10"]), Ok(("tx1", 9)));
+        assert_eq!(layout.validate_site(&["tx1"]).unwrap_err(), "missing column 2 (found 1 columns)");
+        assert_eq!(layout.validate_site(&["", "10"]).unwrap_err(), "empty transcript id in column 1");
+        assert_eq!(layout.validate_site(&["tx1", "NA"]).unwrap_err(), "invalid position 'NA' in column 2");
+        assert_eq!(layout.validate_site(&["tx1", "0"]).unwrap_err(), "position '0' in column 2 is below the coordinate base 1");
+    }
+
+    #[test]
+    fn test_validate_interval() {
+        let layout = SiteLayout { end_column: Some(2), ..Default::default() };
+        assert_eq!(layout.validate_interval(&["tx1", "10", "25"], 10), Ok((10, 25)));
+        assert_eq!(layout.validate_interval(&["tx1", "10"], 10).unwrap_err(), "missing column 3 (found 2 columns)");
+        assert_eq!(layout.validate_interval(&["tx1", "10", "10"], 10).unwrap_err(), "end '10' in column 3 is not after the start");
+        assert_eq!(layout.validate_interval(&["tx1", "10", "."], 10).unwrap_err(), "invalid end '.' in column 3");
+
+        // 1-based, fully closed intervals have the same end as their 0-based, half-open equivalent
+        let one_based = SiteLayout { coord_base: 1, end_column: Some(2), ..Default::default() };
+        let fields = ["tx1", "11", "25"];
+        let (_, start) = one_based.validate_site(&fields).unwrap();
+        assert_eq!(one_based.validate_interval(&fields, start), Ok((10, 25)));
+
+        assert_eq!(SiteLayout::default().validate_interval(&["tx1", "10", "25"], 10), Ok((10, 11)));
+    }
+
+    #[test]
+    fn test_row_validator() {
+        let mut lenient = RowValidator::new("sites.bed", false);
+        assert_eq!(lenient.check(1, Ok::<u64, String>(5)).unwrap(), Some(5));
+        assert_eq!(lenient.check(2, Err::<u64, String>("invalid position 'NA' in column 2".to_string())).unwrap(), None);
+        assert_eq!(lenient.skipped(), 1);
+
+        let mut strict = RowValidator::new("sites.bed", true);
+        let error = strict.check(7, Err::<u64, String>("missing column 2 (found 1 columns)".to_string())).unwrap_err();
+        assert_eq!(error.to_string(), "Input parse error: sites.bed line 7: missing column 2 (found 1 columns)");
+        assert_eq!(error.exit_code(), 5);
+    }
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(parse_position("10", 0), Some(10));
+        assert_eq!(parse_position("10", 1), Some(9));
+        assert_eq!(parse_position("0", 1), None);
+        assert_eq!(parse_position("NA", 0), None);
+    }
+
+    #[test]
+    fn test_read_batch() {
+        let input = "T1\t1\n".repeat(BATCH_SIZE + 2);
+        let mut lines = io::Cursor::new(input).lines().enumerate().map(|(index, line)| (index + 1, line));
+        let mut batch = Vec::new();
+
+        assert!(read_batch(&mut lines, &mut batch).unwrap());
+        assert_eq!(batch.len(), BATCH_SIZE);
+        assert_eq!(batch[0], (1, "T1\t1".to_string()));
+        assert!(read_batch(&mut lines, &mut batch).unwrap());
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[1].0, BATCH_SIZE + 2);
+        assert!(!read_batch(&mut lines, &mut batch).unwrap());
+    }
+
+    #[test]
+    fn test_input_name() {
+        assert_eq!(input_name("-"), "stdin");
+        assert_eq!(input_name("sites.bed"), "sites.bed");
+        assert_eq!(open_input("/nonexistent/sites.bed").err().unwrap().exit_code(), 3);
+    }
+
+    #[test]
+    fn test_named_file() {
+        assert_eq!(named_file("seeds=data/mirna.bed"), ("seeds".to_string(), "data/mirna.bed".to_string()));
+        assert_eq!(named_file("data/clip_peaks.bed.gz"), ("clip_peaks".to_string(), "data/clip_peaks.bed.gz".to_string()));
+        assert_eq!(named_file("./runs/a=b/g4.bed"), ("g4".to_string(), "./runs/a=b/g4.bed".to_string()));
+    }
+
+    #[test]
+    fn test_check_unique_labels() {
+        let command = clap::Command::new("test").arg(clap::Arg::new("track").long("track").action(clap::ArgAction::Append));
+        let matches = command.clone().get_matches_from(["test", "--track", "a/rmsk.bed", "--track", "rmsk_b=b/rmsk.bed"]);
+        assert!(check_unique_labels(&matches, &["track"]).is_ok());
+        let matches = command.get_matches_from(["test", "--track", "a/rmsk.bed", "--track", "b/rmsk.bed.gz"]);
+        assert!(check_unique_labels(&matches, &["track"]).is_err());
+    }
+}
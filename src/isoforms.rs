@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use crate::error::R2dError;
+use crate::liftover::genomic_to_transcript_position;
+use crate::parse_gtf::Transcript;
+
+// tags marking the canonical isoform of a gene in Ensembl and GENCODE annotations
+const CANONICAL_TAGS: [&str; 2] = ["Ensembl_canonical", "MANE_Select"];
+
+// isoforms reported for a genomic site overlapping several transcripts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsoformPolicy {
+    // every overlapping transcript
+    All,
+    // overlapping transcripts tagged as canonical
+    Canonical,
+    // the overlapping transcript of highest priority: canonical, then protein-coding, then longest
+    Best,
+}
+
+pub fn isoform_policy_from_matches(matches: &clap::ArgMatches) -> Result<IsoformPolicy, R2dError> {
+    match matches.get_one::<String>("isoforms").map(|policy| policy.as_str()) {
+        None | Some("all") => Ok(IsoformPolicy::All),
+        Some("canonical") => Ok(IsoformPolicy::Canonical),
+        Some("best") => Ok(IsoformPolicy::Best),
+        Some(other) => Err(R2dError::InputParse(format!("Invalid isoform policy: {}", other))),
+    }
+}
+
+pub fn is_canonical(transcript: &Transcript) -> bool {
+    CANONICAL_TAGS.iter().any(|tag| transcript.has_tag(tag))
+}
+
+// overlapping isoform of a genomic site, with its key in the transcript table and the 0-based transcript position
+pub struct IsoformHit<'a> {
+    pub transcript_id: &'a str,
+    pub transcript: &'a Transcript,
+    pub tx_coord: u64,
+}
+
+// exons of the annotated transcripts in 0-based, half-open genomic coordinates, keyed on chromosome
+pub struct ExonIndex {
+    chromosomes: HashMap<String, ArrayBackedIntervalTree<u64, String>>,
+}
+
+impl ExonIndex {
+    pub fn new(transcripts: &HashMap<String, Transcript>) -> Self {
+        let mut chromosomes: HashMap<String, ArrayBackedIntervalTree<u64, String>> = HashMap::new();
+        for (transcript_id, transcript) in transcripts {
+            for exon in transcript.ordered_exons() {
+                chromosomes.entry(transcript.chromosome.clone()).or_default()
+                    .insert(exon.start - 1..exon.end, transcript_id.clone());
+            }
+        }
+        chromosomes.values_mut().for_each(|tree| tree.index());
+        ExonIndex { chromosomes }
+    }
+
+    // transcripts with an exon containing the site, on its strand when known, in the order given by the policy
+    pub fn overlapping<'a>(
+        &self,
+        transcripts: &'a HashMap<String, Transcript>,
+        chromosome: &str,
+        position: u64,
+        strand: Option<&str>,
+        policy: IsoformPolicy
+    ) -> Vec<IsoformHit<'a>> {
+        let Some(tree) = self.chromosomes.get(chromosome) else {
+            return Vec::new();
+        };
+        let mut hits: Vec<IsoformHit> = tree.find(position..position + 1).iter()
+            .filter_map(|entry| {
+                let (transcript_id, transcript) = transcripts.get_key_value(entry.data())?;
+                if strand.is_some_and(|strand| transcript.strand.as_deref() != Some(strand)) {
+                    return None;
+                }
+                let tx_coord = genomic_to_transcript_position(transcript, position)?;
+                Some(IsoformHit { transcript_id, transcript, tx_coord })
+            })
+            .collect();
+
+        match policy {
+            IsoformPolicy::All => hits.sort_by(|a, b| a.transcript_id.cmp(b.transcript_id)),
+            IsoformPolicy::Canonical => {
+                hits.retain(|hit| is_canonical(hit.transcript));
+                hits.sort_by(|a, b| a.transcript_id.cmp(b.transcript_id));
+            }
+            IsoformPolicy::Best => {
+                hits.sort_by_key(|hit| (
+                    !is_canonical(hit.transcript),
+                    hit.transcript.cds_len.is_none_or(|cds_len| cds_len == 0),
+                    std::cmp::Reverse(hit.transcript.transcript_length.unwrap_or(0)),
+                    hit.transcript_id,
+                ));
+                hits.truncate(1);
+            }
+        }
+        hits
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_gtf::Exon;
+
+    fn create_test_transcript(strand: &str, exons: Vec<(u64, u64)>, cds_len: Option<u64>, tag: &str) -> Transcript {
+        let attributes: HashMap<String, String> = [("tag".to_string(), tag.to_string())].into_iter().collect();
+        let exons: Vec<Exon> = exons.into_iter().map(|(start, end)| Exon {
+            start,
+            end,
+            length: end - start + 1,
+            strand: strand.to_string(),
+            attributes: attributes.clone(),
+            feature: Some("exon".to_string()),
+            ..Default::default()
+        }).collect();
+        Transcript {
+            chromosome: "chr1".to_string(),
+            strand: Some(strand.to_string()),
+            transcript_length: Some(exons.iter().map(|exon| exon.length).sum()),
+            cds_len,
+            exons,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_overlapping_isoforms() {
+        let mut transcripts = HashMap::new();
+        transcripts.insert("T1".to_string(), create_test_transcript("+", vec![(101, 200), (301, 400)], None, "basic"));
+        transcripts.insert("T2".to_string(), create_test_transcript("+", vec![(151, 400)], Some(90), "basic"));
+        transcripts.insert("T3".to_string(), create_test_transcript("+", vec![(101, 200), (351, 400)], Some(60), "basic,Ensembl_canonical"));
+        transcripts.insert("T4".to_string(), create_test_transcript("-", vec![(101, 400)], None, "basic"));
+        let index = ExonIndex::new(&transcripts);
+
+        let ids = |hits: Vec<IsoformHit>| -> Vec<(String, u64)> {
+            hits.iter().map(|hit| (hit.transcript_id.to_string(), hit.tx_coord)).collect()
+        };
+        assert_eq!(
+            ids(index.overlapping(&transcripts, "chr1", 160, Some("+"), IsoformPolicy::All)),
+            vec![("T1".to_string(), 60), ("T2".to_string(), 10), ("T3".to_string(), 60)]
+        );
+        // the site lies in the intron of T3
+        assert_eq!(
+            ids(index.overlapping(&transcripts, "chr1", 310, None, IsoformPolicy::All)),
+            vec![("T1".to_string(), 110), ("T2".to_string(), 160), ("T4".to_string(), 89)]
+        );
+        assert_eq!(ids(index.overlapping(&transcripts, "chr1", 160, None, IsoformPolicy::Canonical)), vec![("T3".to_string(), 60)]);
+        assert_eq!(ids(index.overlapping(&transcripts, "chr1", 160, None, IsoformPolicy::Best)), vec![("T3".to_string(), 60)]);
+        // without a canonical isoform, the longest protein-coding isoform is preferred
+        assert_eq!(ids(index.overlapping(&transcripts, "chr1", 310, None, IsoformPolicy::Best)), vec![("T2".to_string(), 160)]);
+        assert!(index.overlapping(&transcripts, "chr1", 310, None, IsoformPolicy::Canonical).is_empty());
+        assert!(index.overlapping(&transcripts, "chr2", 160, None, IsoformPolicy::All).is_empty());
+    }
+}
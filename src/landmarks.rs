@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use crate::bed::for_each_bed_record;
+use crate::error::{R2dError, open_file};
+
+// landmark interval in 0-based, half-open transcript coordinates
+#[derive(Debug, Clone)]
+struct Landmark {
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+// landmarks of one transcript sorted by start, with for each prefix of the list the landmark reaching furthest
+// downstream, so that long landmarks containing the site are found by the binary search
+#[derive(Debug, Default)]
+struct TranscriptLandmarks {
+    landmarks: Vec<Landmark>,
+    furthest_end: Vec<usize>,
+}
+
+impl TranscriptLandmarks {
+    fn index(&mut self) {
+        self.landmarks.sort_by_key(|landmark| (landmark.start, landmark.end));
+        self.furthest_end = Vec::with_capacity(self.landmarks.len());
+        for (i, landmark) in self.landmarks.iter().enumerate() {
+            let furthest = match self.furthest_end.last() {
+                Some(&j) if self.landmarks[j].end >= landmark.end => j,
+                _ => i,
+            };
+            self.furthest_end.push(furthest);
+        }
+    }
+
+    // signed distance from the nearest landmark to the site: 0 within the landmark, negative when the
+    // site lies upstream of it and positive when it lies downstream
+    fn nearest(&self, tx_coord: u64) -> Option<(i64, &str)> {
+        let index = self.landmarks.partition_point(|landmark| landmark.start <= tx_coord);
+
+        // landmark starting at or before the site that reaches furthest downstream
+        let upstream = index.checked_sub(1).map(|i| {
+            let landmark = &self.landmarks[self.furthest_end[i]];
+            let distance = if tx_coord < landmark.end { 0 } else { (tx_coord - (landmark.end - 1)) as i64 };
+            (distance, landmark.name.as_str())
+        });
+        // first landmark starting after the site
+        let downstream = self.landmarks.get(index)
+            .map(|landmark| (tx_coord as i64 - landmark.start as i64, landmark.name.as_str()));
+
+        match (upstream, downstream) {
+            (Some(upstream), Some(downstream)) if downstream.0.abs() < upstream.0.abs() => Some(downstream),
+            (Some(upstream), _) => Some(upstream),
+            (None, downstream) => downstream,
+        }
+    }
+}
+
+// landmarks in transcript coordinates (e.g. miRNA seed matches, CLIP peaks, uORF starts), read from a BED file
+// with the transcript id in the first column and an optional name in the fourth
+pub struct LandmarkSet {
+    pub label: String,
+    transcripts: HashMap<String, TranscriptLandmarks>,
+}
+
+impl LandmarkSet {
+    pub fn from_file(label: &str, file_path: &str, has_version: bool) -> Result<Self, R2dError> {
+        let reader = BufReader::new(open_file(file_path)?);
+        Self::from_reader(label, file_path, reader, has_version)
+    }
+
+    pub fn from_reader<R: BufRead>(label: &str, file_path: &str, reader: R, has_version: bool) -> Result<Self, R2dError> {
+        let mut transcripts: HashMap<String, TranscriptLandmarks> = HashMap::new();
+
+        for_each_bed_record(file_path, reader, |record| {
+            let transcript_id = if has_version { record.chrom } else { record.chrom.split('.').next().unwrap() };
+            let name = record.name.unwrap_or("NA").to_string();
+            transcripts.entry(transcript_id.to_string()).or_default()
+                .landmarks.push(Landmark { start: record.start, end: record.end, name });
+        })?;
+
+        transcripts.values_mut().for_each(|landmarks| landmarks.index());
+
+        Ok(LandmarkSet { label: label.to_string(), transcripts })
+    }
+
+    // signed distance to the nearest landmark of the same transcript, and its name
+    pub fn nearest(&self, transcript_id: &str, tx_coord: u64) -> Option<(i64, &str)> {
+        self.transcripts.get(transcript_id)?.nearest(tx_coord)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_landmarks() -> LandmarkSet {
+        let bed = "track name=seeds\nT1.1\t10\t20\tseed_a\nT1.1\t100\t110\tseed_b\nT1.1\t40\t200\tuorf\nT2\t5\t6\n";
+        LandmarkSet::from_reader("seeds", "seeds.bed", bed.as_bytes(), false).unwrap()
+    }
+
+    #[test]
+    fn test_nearest_landmark() {
+        let landmarks = create_test_landmarks();
+        assert_eq!(landmarks.nearest("T1", 5), Some((-5, "seed_a")));
+        assert_eq!(landmarks.nearest("T1", 10), Some((0, "seed_a")));
+        assert_eq!(landmarks.nearest("T1", 19), Some((0, "seed_a")));
+        assert_eq!(landmarks.nearest("T1", 25), Some((6, "seed_a")));
+        assert_eq!(landmarks.nearest("T1", 35), Some((-5, "uorf")));
+        // the long uORF landmark contains sites past the shorter landmarks that start after it
+        assert_eq!(landmarks.nearest("T1", 150), Some((0, "uorf")));
+        assert_eq!(landmarks.nearest("T1", 210), Some((11, "uorf")));
+        assert_eq!(landmarks.nearest("T2", 9), Some((4, "NA")));
+        assert_eq!(landmarks.nearest("T3", 9), None);
+    }
+
+    #[test]
+    fn test_malformed_landmarks() {
+        let error = LandmarkSet::from_reader("seeds", "seeds.bed", "T1\t10\tend\n".as_bytes(), false).err().unwrap();
+        assert_eq!(error.to_string(), "Input parse error: seeds.bed line 1: invalid position 'end'");
+        assert!(LandmarkSet::from_reader("seeds", "seeds.bed", "T1\t10\n".as_bytes(), false).is_err());
+        assert!(LandmarkSet::from_reader("seeds", "seeds.bed", "T1\t10\t10\n".as_bytes(), false).is_err());
+    }
+}
@@ -1,38 +1,55 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write, BufWriter};
-use std::error::Error;
+use std::io::{BufRead, ErrorKind, Write, BufWriter};
+use log::{debug, warn};
+use crate::error::{R2dError, create_file};
 use crate::parse_gtf::{Transcript, read_annotation_file};
+use crate::chain::{ChainMap, UnmappedReason};
+use crate::tracks::tracks_from_matches;
+use crate::input::{BATCH_SIZE, RowValidator, SiteRecord, check_unique_labels, coord_base_from_matches, input_file_from_matches, input_format_from_matches, input_name, open_input, read_batch, site_layout_from_matches};
 use std::collections::HashMap;
 use rayon::prelude::*;
 
-fn convert_transcriptomic_to_genomic_coordinates(
-    site_fields: &[&str],
+// genomic position of a transcriptomic site, in 0-based coordinates
+#[derive(Debug, PartialEq)]
+struct GenomicSite {
+    chromosome: String,
+    position: u64,
+    strand: String,
+}
+
+// the record position is already 0-based, whatever the input format
+fn transcriptomic_to_genomic_site(
+    record: &SiteRecord,
     annotations: &HashMap<String, Transcript>,
     has_version: bool
-) -> Option<String> {
-    if site_fields.len() < 4 {
-        return None;
-    }
-
-    let transcript_id_with_version = site_fields[0];
+) -> Option<GenomicSite> {
+    let position = record.position;
     let transcript_id = if has_version {
-        transcript_id_with_version
+        record.transcript_id
     } else {
-        transcript_id_with_version.split('.').next()?
+        record.transcript_id.split('.').next()?
     };
 
-    let position: u64 = site_fields[1].parse().ok()?;
-    let mut current_position = 0;
-
     let transcript = annotations.get(transcript_id)?;
-    let mut exons = transcript.exons.clone();
-    if transcript.strand.as_deref() == Some("-") {
-        exons.sort_by(|a, b| b.start.cmp(&a.start));
-    } else {
-        exons.sort_by(|a, b| a.start.cmp(&b.start));
+
+    match transcript_to_genomic_position(transcript, position) {
+        Some(genomic_position) => Some(GenomicSite {
+            chromosome: transcript.chromosome.clone(),
+            position: genomic_position,
+            strand: transcript.strand.clone().unwrap_or_default(),
+        }),
+        None => {
+            warn!("No associated transcripts found for site '{}'.", transcript_id);
+            None
+        }
     }
+}
+
+// walk the exons in transcript order to convert a 0-based transcript position into a 0-based genomic position
+// UTR and CDS features overlap the exons, so only exon features are walked
+pub fn transcript_to_genomic_position(transcript: &Transcript, position: u64) -> Option<u64> {
+    let mut current_position = 0;
 
-    for exon_data in &exons {
+    for exon_data in transcript.ordered_exons() {
         let exon_length = exon_data.end - exon_data.start + 1;
         if current_position + exon_length > position {
             let genomic_position = if transcript.strand.as_deref() == Some("+") {
@@ -40,75 +57,233 @@ fn convert_transcriptomic_to_genomic_coordinates(
             } else {
                 exon_data.end - (position - current_position) - 1
             };
-
-            let chrom = &transcript.chromosome;
-            let genomic_strand = &transcript.strand;
-            let additional_columns = site_fields[1..].join("\t");
-
-            return Some(format!(
-                "{}\t{}\t{}\t\t\t{}\t{}\t{}",
-                chrom, genomic_position, genomic_position + 1, genomic_strand.as_deref().unwrap_or(""), site_fields[0], additional_columns
-            ));
+            return Some(genomic_position);
         }
         current_position += exon_length;
     }
 
-    eprintln!("Warning: No associated transcripts found for site '{}'.", transcript_id);
     None
 }
-pub fn run_liftover(matches: &clap::ArgMatches, has_header: bool, has_version: bool) -> Result<(), Box<dyn Error>> {
+
+// inverse of transcript_to_genomic_position: 0-based transcript position of a 0-based genomic position, None when
+// the position lies outside the exons of the transcript
+pub fn genomic_to_transcript_position(transcript: &Transcript, genomic_position: u64) -> Option<u64> {
+    let mut current_position = 0;
+    for exon in transcript.ordered_exons() {
+        let (start, end) = (exon.start - 1, exon.end - 1);
+        if (start..=end).contains(&genomic_position) {
+            let offset = if transcript.strand.as_deref() == Some("-") { end - genomic_position } else { genomic_position - start };
+            return Some(current_position + offset);
+        }
+        current_position += exon.end - exon.start + 1;
+    }
+    None
+}
+
+// BED6 columns of the lifted site, followed by the input columns of the site record
+fn format_lifted_site(site: &GenomicSite, record: &SiteRecord) -> String {
+    format!(
+        "{}\t{}\t{}\t\t\t{}\t{}",
+        site.chromosome, site.position, site.position + 1, site.strand, record.columns
+    )
+}
+
+// outcome of lifting a single input row
+enum LiftedRow {
+    Lifted(String),
+    // the site could not be converted with the chain file
+    Unmapped(String, UnmappedReason),
+    // malformed row, with its line number and the problem found
+    Invalid(usize, String),
+    // the transcript is not annotated, or the position is beyond its end
+    NotFound,
+}
+
+// convert a lifted site into the coordinates of a second assembly
+fn apply_chain(site: GenomicSite, chain_map: &ChainMap) -> Result<GenomicSite, UnmappedReason> {
+    let mapping = chain_map.map_position(&site.chromosome, site.position)?;
+    let strand = match (site.strand.as_str(), mapping.reverse) {
+        ("+", true) => "-".to_string(),
+        ("-", true) => "+".to_string(),
+        (strand, _) => strand.to_string(),
+    };
+    Ok(GenomicSite { chromosome: mapping.chromosome, position: mapping.position, strand })
+}
+
+pub fn run_liftover(matches: &clap::ArgMatches, has_header: bool, has_version: bool) -> Result<(), R2dError> {
 
     let gtf_file: String = matches.get_one::<String>("gtf").unwrap().to_string();
-    let input_file: String = matches.get_one::<String>("input").unwrap().to_string();
+    let input_file = input_file_from_matches(matches);
     let output_file: Option<String> = matches.get_one::<String>("output").map(|s: &String| s.to_string());
     
+    let coord_base = coord_base_from_matches(matches)?;
+    check_unique_labels(matches, &["track"])?;
     let annotations = read_annotation_file(&gtf_file, true, has_version)?;
 
-    let mut input_reader = BufReader::with_capacity(512 * 1024, File::open(input_file.clone())?);
+    // optional conversion to a second assembly after the transcript to genome liftover
+    let chain_map = match matches.get_one::<String>("chain") {
+        Some(chain_file) => Some(ChainMap::from_file(chain_file)?),
+        None => None,
+    };
+    // genomic features overlapped with each lifted site
+    let tracks = tracks_from_matches(matches)?;
+    let mut unmapped_writer = match matches.get_one::<String>("unmapped") {
+        Some(file_name) => Some(BufWriter::new(create_file(file_name)?)),
+        None => None,
+    };
+
+    let mut input_reader = open_input(&input_file)?;
     let mut output_writer = BufWriter::with_capacity(512 * 1024, match output_file {
-        Some(file_name) => Box::new(File::create(file_name)?) as Box<dyn Write>,
+        Some(file_name) => Box::new(create_file(&file_name)?) as Box<dyn Write>,
         None => Box::new(std::io::stdout()) as Box<dyn Write>,
     });
 
+    // the tables of modification callers carry their own header row
+    let has_header = input_format_from_matches(matches)?.has_header().unwrap_or(has_header);
+    let mut header = String::new();
     if has_header {
-        let mut header = String::new();
         input_reader.read_line(&mut header)?;
-        writeln!(output_writer, "chromosome\tstart\tend\tname\tscore\tstrand\t{}", header.trim())?;
     }
+    // the id and position columns may be selected by name, so the layout is resolved after reading the header
+    let layout = site_layout_from_matches(matches, if has_header { Some(header.as_str()) } else { None })?;
 
-    // Process the rest of the lines in parallel
-    let results: Vec<_> = input_reader.lines()
-        .par_bridge()
-        .filter_map(|line| {
-            let line = line.ok()?;
-            let site_fields: Vec<&str> = line.trim().split('\t').collect();
-            convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, has_version)
-        })
-        .collect();
-
-    // Write results
-    for result in results {
-        writeln!(output_writer, "{}", result)?;
+    if let Some(header) = layout.output_header(if has_header { Some(header.as_str()) } else { None }) {
+        let coord_base_column = if coord_base.is_some() { "\tcoord_base" } else { "" };
+        let track_columns: String = tracks.iter().flat_map(|track| track.columns()).map(|column| format!("\t{}", column)).collect();
+        writeln!(output_writer, "chromosome\tstart\tend\tname\tscore\tstrand\t{}{}{}", header, track_columns, coord_base_column)?;
+        if let Some(writer) = unmapped_writer.as_mut() {
+            writeln!(writer, "{}\treason", header)?;
+        }
+    }
+
+    // Process the rest of the lines in parallel batches, keeping the line numbers for error reporting
+    let mut validator = RowValidator::from_matches(matches, input_name(&input_file));
+    let first_line = if has_header { 2 } else { 1 };
+    let mut lines = input_reader.lines().enumerate().map(|(index, line)| (index + first_line, line));
+    let mut batch: Vec<(usize, String)> = Vec::with_capacity(BATCH_SIZE);
+    let mut site_count = 0;
+    let mut lifted_count = 0;
+    let mut unmapped_counts: HashMap<UnmappedReason, usize> = HashMap::new();
+    'batches: while read_batch(&mut lines, &mut batch)? {
+        let results: Vec<LiftedRow> = batch.par_iter()
+            .filter_map(|(line_number, line)| {
+                if line.trim().is_empty() {
+                    return None;
+                }
+                let site_fields: Vec<&str> = layout.split(line.trim());
+                if let Err(message) = layout.validate_site(&site_fields) {
+                    return Some(LiftedRow::Invalid(*line_number, message));
+                }
+                let Some(record) = layout.record(&site_fields) else {
+                    return Some(LiftedRow::NotFound);
+                };
+                let Some(mut site) = transcriptomic_to_genomic_site(&record, &annotations, has_version) else {
+                    return Some(LiftedRow::NotFound);
+                };
+                if let Some(chain_map) = &chain_map {
+                    match apply_chain(site, chain_map) {
+                        Ok(converted) => site = converted,
+                        Err(reason) => return Some(LiftedRow::Unmapped(record.columns, reason)),
+                    }
+                }
+                // genomic tracks are overlapped in the output assembly
+                let mut lifted = format_lifted_site(&site, &record);
+                for track in &tracks {
+                    for field in track.fields(&site.chromosome, site.position) {
+                        lifted.push('\t');
+                        lifted.push_str(&field);
+                    }
+                }
+                // record the input coordinate convention when it was given explicitly
+                match coord_base {
+                    Some(base) => Some(LiftedRow::Lifted(format!("{}\t{}", lifted, base))),
+                    None => Some(LiftedRow::Lifted(lifted)),
+                }
+            })
+            .collect();
+
+        // results are written in input order as each batch completes, so memory stays bounded on large inputs
+        for result in results {
+            match result {
+                LiftedRow::Lifted(lifted) => {
+                    site_count += 1;
+                    lifted_count += 1;
+                    if let Err(e) = writeln!(output_writer, "{}", lifted) {
+                        // a closed pipe (e.g. into head) ends the output early without an error
+                        if e.kind() == ErrorKind::BrokenPipe {
+                            break 'batches;
+                        }
+                        return Err(e.into());
+                    }
+                }
+                LiftedRow::Unmapped(line, reason) => {
+                    site_count += 1;
+                    *unmapped_counts.entry(reason).or_insert(0) += 1;
+                    if let Some(writer) = unmapped_writer.as_mut() {
+                        writeln!(writer, "{}\t{}", line, reason)?;
+                    }
+                }
+                LiftedRow::Invalid(line_number, message) => {
+                    validator.check::<()>(line_number, Err(message))?;
+                }
+                LiftedRow::NotFound => site_count += 1,
+            }
+        }
+    }
+    match output_writer.flush() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+
+    validator.report();
+
+    // report sites that could not be converted with the chain file
+    if !unmapped_counts.is_empty() {
+        warn!("{} sites could not be converted with the chain file:", unmapped_counts.values().sum::<usize>());
+        for (reason, count) in &unmapped_counts {
+            warn!("- {}: {} sites", reason, count);
+        }
+    }
+
+    // with a chain file, sites matching a transcript may all have been dropped by the conversion instead
+    let chain_dropped: usize = unmapped_counts.values().sum();
+    if lifted_count == 0 && chain_dropped > 0 {
+        let hint = match matches.get_one::<String>("unmapped") {
+            Some(unmapped_file) => format!("see {} for the reason of each site", unmapped_file),
+            None => "rerun with -u/--unmapped to write the reason of each site".to_string(),
+        };
+        return Err(R2dError::Mapping(format!(
+            "none of the {} sites in {} could be lifted, the chain file dropped {} of them; {}",
+            site_count, input_name(&input_file), chain_dropped, hint
+        )));
+    }
+
+    // an empty output usually means the annotation does not match the input transcriptome
+    if lifted_count == 0 && site_count > 0 {
+        return Err(R2dError::Mapping(format!(
+            "none of the {} sites in {} could be lifted to the genome, check that the GTF matches the transcriptome and the -t/--transcript-version flag",
+            site_count, input_name(&input_file)
+        )));
     }
 
     Ok(())
 }
 
 pub fn preview_annotations(annotations: &HashMap<String, Transcript>) {
-    println!("Number of annotations: {}", annotations.len()); // Add this line
+    debug!("Number of annotations: {}", annotations.len());
     for (key, transcript) in annotations {
-        println!("Annotations start");
-        println!("Transcript ID: {}", key);
-        println!("{:#?}", transcript);
-        println!("Annotations end");
+        debug!("Annotations start");
+        debug!("Transcript ID: {}", key);
+        debug!("{:#?}", transcript);
+        debug!("Annotations end");
     }
 }
 
 pub fn print_exon_info(annotations: &HashMap<String, Transcript>) {
     for (key, transcript) in annotations {
-        println!("Transcript ID: {}", key);
+        debug!("Transcript ID: {}", key);
         for (i, exon_data) in transcript.exons.iter().enumerate() {
-            println!("Exon {}: start={}, end={}", i + 1, exon_data.start, exon_data.end);
+            debug!("Exon {}: start={}, end={}", i + 1, exon_data.start, exon_data.end);
         }
     }
 }
@@ -119,6 +294,26 @@ mod tests {
     use super::*;
     use tempfile;
     use crate::parse_gtf::Exon;
+    use crate::input::SiteLayout;
+
+    // mock of the liftover subcommand arguments
+    fn create_test_command() -> clap::Command {
+        use clap::{Arg, Command};
+
+        Command::new("test")
+            .arg(Arg::new("gtf").short('g').long("gtf").required(true))
+            .arg(Arg::new("input").short('i').long("input").required(true))
+            .arg(Arg::new("output").short('o').long("output"))
+            .arg(Arg::new("chain").short('c').long("chain"))
+            .arg(Arg::new("unmapped").short('u').long("unmapped"))
+            .arg(Arg::new("coord-base").long("coord-base"))
+            .arg(Arg::new("id-column").long("id-column"))
+            .arg(Arg::new("pos-column").long("pos-column"))
+            .arg(Arg::new("delimiter").long("delimiter"))
+            .arg(Arg::new("input-format").long("input-format"))
+            .arg(Arg::new("strict").long("strict").action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("track").long("track").action(clap::ArgAction::Append))
+    }
 
     fn create_test_transcript(chromosome: &str, strand: &str, exons: Vec<(u64, u64)>) -> Transcript {
         Transcript {
@@ -158,7 +353,9 @@ mod tests {
         annotations.insert("transcript1".to_string(), create_test_transcript("chr1", "+", vec![(100, 200), (300, 400)]));
         
         let site_fields = vec!["transcript1", "50", "A", "T"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, false);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, false).map(|site| format_lifted_site(&site, &record));
         assert_eq!(result, Some("chr1\t149\t150\t\t\t+\ttranscript1\t50\tA\tT".to_string()));
     }
 
@@ -168,7 +365,9 @@ mod tests {
         annotations.insert("transcript1".to_string(), create_test_transcript("chr1", "-", vec![(100, 200), (300, 400)]));
         
         let site_fields = vec!["transcript1", "50", "A", "T"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, false);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, false).map(|site| format_lifted_site(&site, &record));
         assert_eq!(result, Some("chr1\t349\t350\t\t\t-\ttranscript1\t50\tA\tT".to_string()));
     }
 
@@ -178,15 +377,36 @@ mod tests {
         annotations.insert("transcript1.1".to_string(), create_test_transcript("chr1", "+", vec![(100, 200)]));
         
         let site_fields = vec!["transcript1.1", "50", "A", "T"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, true);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, true).map(|site| format_lifted_site(&site, &record));
         assert_eq!(result, Some("chr1\t149\t150\t\t\t+\ttranscript1.1\t50\tA\tT".to_string()));
     }
 
+    #[test]
+    fn test_convert_transcriptomic_to_genomic_coordinates_one_based() {
+        let mut annotations = HashMap::new();
+        annotations.insert("transcript1".to_string(), create_test_transcript("chr1", "+", vec![(100, 200), (300, 400)]));
+
+        // 1-based position 51 is the same site as 0-based position 50
+        let one_based = SiteLayout { coord_base: 1, ..Default::default() };
+        let site_fields = vec!["transcript1", "51", "A", "T"];
+        let record = one_based.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, false).map(|site| format_lifted_site(&site, &record));
+        assert_eq!(result, Some("chr1\t149\t150\t\t\t+\ttranscript1\t51\tA\tT".to_string()));
+
+        // position 0 does not exist in 1-based coordinates
+        let site_fields = vec!["transcript1", "0", "A", "T"];
+        assert!(one_based.record(&site_fields).is_none());
+    }
+
     #[test]
     fn test_convert_transcriptomic_to_genomic_coordinates_invalid_transcript() {
         let annotations = HashMap::new();
         let site_fields = vec!["invalid_transcript", "50", "A", "T"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, false);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, false).map(|site| format_lifted_site(&site, &record));
         assert_eq!(result, None);
     }
 
@@ -196,17 +416,56 @@ mod tests {
         annotations.insert("transcript1".to_string(), create_test_transcript("chr1", "+", vec![(100, 200)]));
         
         let site_fields = vec!["transcript1", "150", "A", "T"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, false);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, false).map(|site| format_lifted_site(&site, &record));
         assert_eq!(result, None);
     }
 
+    #[test]
+    fn test_genomic_to_transcript_position() {
+        for strand in ["+", "-"] {
+            let transcript = create_test_transcript("chr1", strand, vec![(100, 200), (300, 400)]);
+            for position in [0, 50, 100, 101, 201] {
+                let genomic_position = transcript_to_genomic_position(&transcript, position).unwrap();
+                assert_eq!(genomic_to_transcript_position(&transcript, genomic_position), Some(position));
+            }
+            // intronic and intergenic positions
+            assert_eq!(genomic_to_transcript_position(&transcript, 250), None);
+            assert_eq!(genomic_to_transcript_position(&transcript, 400), None);
+        }
+    }
+
+    #[test]
+    fn test_genomic_to_transcript_position_with_utr_features() {
+        // UTR and CDS features overlapping the exons are not walked in either direction
+        for strand in ["+", "-"] {
+            let mut transcript = create_test_transcript("chr1", strand, vec![(101, 200), (301, 400)]);
+            for (feature, start, end) in [("UTR", 101, 150), ("CDS", 151, 360), ("UTR", 361, 400)] {
+                let mut exon = transcript.exons[0].clone();
+                exon.feature = Some(feature.to_string());
+                exon.feature_type = feature.to_string();
+                (exon.start, exon.end, exon.length) = (start, end, end - start + 1);
+                transcript.exons.push(exon);
+            }
+            for position in [0, 49, 99, 100, 119, 199] {
+                let genomic_position = transcript_to_genomic_position(&transcript, position).unwrap();
+                assert_eq!(genomic_to_transcript_position(&transcript, genomic_position), Some(position));
+            }
+            assert_eq!(transcript_to_genomic_position(&transcript, 120), Some(if strand == "+" { 320 } else { 179 }));
+            assert_eq!(transcript_to_genomic_position(&transcript, 200), None);
+        }
+    }
+
     #[test]
     fn test_convert_transcriptomic_to_genomic_coordinates_real_data() {
         let mut annotations = HashMap::new();
         annotations.insert("ENST00000400109.2".to_string(), create_test_transcript("13", "-", vec![(19304593, 19305625)]));
 
         let site_fields = vec!["ENST00000400109.2", "87", "88", "a", "10", "+", "10", "0.00"];
-        let result = convert_transcriptomic_to_genomic_coordinates(&site_fields, &annotations, true);
+        let layout = SiteLayout::default();
+        let record = layout.record(&site_fields).unwrap();
+        let result = transcriptomic_to_genomic_site(&record, &annotations, true).map(|site| format_lifted_site(&site, &record));
         
         assert_eq!(
             result,
@@ -216,8 +475,6 @@ mod tests {
 
     #[test]
     fn test_run_liftover() {
-        use clap::{Arg, Command};
-    
         // Mock input data
         let input_data = "transcript\tstart\tend\tbase\tcoverage\tstrand\tN_valid_cov\tfraction_modified\nENST00000400109.2\t87\t88\ta\t10\t+\t10\t0.00\n";
         let gtf_data = "13\thavana\ttranscript\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; gene_version \"2\"; transcript_id \"ENST00000400109\"; transcript_version \"2\"; gene_name \"MRPL3P1\"; gene_source \"havana\"; gene_biotype \"processed_pseudogene\"; transcript_name \"MRPL3P1-201\"; transcript_source \"havana\"; transcript_biotype \"processed_pseudogene\"; tag \"basic\"; tag \"Ensembl_canonical\"; transcript_support_level \"NA\";\n13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; gene_version \"2\"; transcript_id \"ENST00000400109\"; transcript_version \"2\"; exon_number \"1\"; gene_name \"MRPL3P1\"; gene_source \"havana\"; gene_biotype \"processed_pseudogene\"; transcript_name \"MRPL3P1-201\"; transcript_source \"havana\"; transcript_biotype \"processed_pseudogene\"; exon_id \"ENSE00001541585\"; exon_version \"2\"; tag \"basic\"; tag \"Ensembl_canonical\"; transcript_support_level \"NA\";\n";
@@ -232,10 +489,7 @@ mod tests {
         std::fs::write(&gtf_path, gtf_data).unwrap();
     
         // Create mock ArgMatches
-        let matches = Command::new("test")
-            .arg(Arg::new("gtf").short('g').long("gtf").required(true))
-            .arg(Arg::new("input").short('i').long("input").required(true))
-            .arg(Arg::new("output").short('o').long("output"))
+        let matches = create_test_command()
             .get_matches_from(vec![
                 "test",
                 "-g", gtf_path.to_str().unwrap(),
@@ -256,5 +510,199 @@ mod tests {
         let expected_output = "chromosome\tstart\tend\tname\tscore\tstrand\ttranscript\tstart\tend\tbase\tcoverage\tstrand\tN_valid_cov\tfraction_modified\n13\t19305537\t19305538\t\t\t-\tENST00000400109.2\t87\t88\ta\t10\t+\t10\t0.00\n";
         assert_eq!(output_content, expected_output);
     }
-}
 
+    #[test]
+    fn test_run_liftover_with_chain() {
+        let input_data = "ENST00000400109.2\t87\t88\ta\nENST00000400109.2\t10\t11\tb\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+        // reverse strand chain covering 19305500-19305600 only
+        let chain_data = "chain 1000 13 115169878 + 19305500 19305600 chr13 114364328 - 1000 1100 1\n100\n\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.txt");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let chain_path = temp_dir.path().join("test.chain");
+        let output_path = temp_dir.path().join("output.txt");
+        let unmapped_path = temp_dir.path().join("unmapped.txt");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&chain_path, chain_data).unwrap();
+
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", input_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+                "-c", chain_path.to_str().unwrap(),
+                "-u", unmapped_path.to_str().unwrap(),
+            ]);
+
+        run_liftover(&matches, false, false).unwrap();
+
+        // 19305537 on the minus strand maps to the reverse strand of the query
+        let output_content = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(output_content, "chr13\t114363290\t114363291\t\t\t+\tENST00000400109.2\t87\t88\ta\n");
+
+        let unmapped_content = std::fs::read_to_string(unmapped_path).unwrap();
+        assert_eq!(unmapped_content, "ENST00000400109.2\t10\t11\tb\tno_chain_at_position\n");
+    }
+
+    #[test]
+    fn test_run_liftover_all_sites_dropped_by_chain() {
+        let input_data = "ENST00000400109.2\t10\t11\tb\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+        let chain_data = "chain 1000 13 115169878 + 19305500 19305600 chr13 114364328 - 1000 1100 1\n100\n\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.txt");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let chain_path = temp_dir.path().join("test.chain");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&chain_path, chain_data).unwrap();
+
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", input_path.to_str().unwrap(),
+                "-o", temp_dir.path().join("output.txt").to_str().unwrap(),
+                "-c", chain_path.to_str().unwrap(),
+            ]);
+
+        // the site matches the GTF, so the error points at the chain file rather than the annotation
+        let error = run_liftover(&matches, false, false).unwrap_err();
+        assert!(matches!(error, R2dError::Mapping(_)));
+        assert!(error.to_string().ends_with("the chain file dropped 1 of them; rerun with -u/--unmapped to write the reason of each site"));
+    }
+
+    #[test]
+    fn test_run_liftover_csv_columns_by_index() {
+        let input_data = "0.95,87,ENST00000400109.2\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.csv");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let output_path = temp_dir.path().join("output.txt");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", input_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+                "--delimiter", ",",
+                "--id-column", "3",
+                "--pos-column", "2",
+            ]);
+
+        run_liftover(&matches, false, false).unwrap();
+
+        let output_content = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(output_content, "13\t19305537\t19305538\t\t\t-\t0.95\t87\tENST00000400109.2\n");
+    }
+
+    #[test]
+    fn test_run_liftover_strict() {
+        let input_data = "ENST00000400109.2\t87\t88\ta\nENST00000400109.2\tNA\t88\ta\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.txt");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let output_path = temp_dir.path().join("output.txt");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+
+        let args = vec![
+            "test",
+            "-g", gtf_path.to_str().unwrap(),
+            "-i", input_path.to_str().unwrap(),
+            "-o", output_path.to_str().unwrap(),
+        ];
+
+        // the malformed row is skipped by default
+        run_liftover(&create_test_command().get_matches_from(args.clone()), false, false).unwrap();
+        let output_content = std::fs::read_to_string(&output_path).unwrap();
+        assert_eq!(output_content, "13\t19305537\t19305538\t\t\t-\tENST00000400109.2\t87\t88\ta\n");
+
+        let mut strict_args = args;
+        strict_args.push("--strict");
+        let error = run_liftover(&create_test_command().get_matches_from(strict_args), false, false).unwrap_err();
+        assert!(error.to_string().ends_with("line 2: invalid position 'NA' in column 2"));
+    }
+
+    #[test]
+    fn test_run_liftover_no_sites_mapped() {
+        let input_data = "ENST00000999999.1\t87\t88\ta\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.txt");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", input_path.to_str().unwrap(),
+                "-o", temp_dir.path().join("output.txt").to_str().unwrap(),
+            ]);
+
+        let error = run_liftover(&matches, false, false).unwrap_err();
+        assert!(matches!(error, R2dError::Mapping(_)));
+        assert_eq!(error.exit_code(), 6);
+
+        // a missing input file is an I/O error
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", temp_dir.path().join("missing.txt").to_str().unwrap(),
+            ]);
+        assert_eq!(run_liftover(&matches, false, false).unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn test_run_liftover_tracks() {
+        let input_data = "transcript\tstart\tend\nENST00000400109.2\t87\t88\nENST00000400109.2\t10\t11\n";
+        let gtf_data = "13\thavana\texon\t19304593\t19305625\t.\t-\t.\tgene_id \"ENSG00000215349\"; transcript_id \"ENST00000400109\"; gene_name \"MRPL3P1\"; transcript_biotype \"processed_pseudogene\";\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_path = temp_dir.path().join("input.txt");
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let output_path = temp_dir.path().join("output.txt");
+        let repeats_path = temp_dir.path().join("repeats.hg38.bed");
+        let conserved_path = temp_dir.path().join("elements.bed");
+        std::fs::write(&input_path, input_data).unwrap();
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&repeats_path, "13\t19305530\t19305540\tAluSx\n").unwrap();
+        std::fs::write(&conserved_path, "13\t19305600\t19305610\n1\t100\t200\n").unwrap();
+        let conserved_spec = format!("phastcons={}", conserved_path.to_str().unwrap());
+
+        let matches = create_test_command()
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", input_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+                "--track", repeats_path.to_str().unwrap(),
+                "--track", &conserved_spec,
+            ]);
+        run_liftover(&matches, true, false).unwrap();
+
+        let output = std::fs::read_to_string(output_path).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, vec![
+            "chromosome\tstart\tend\tname\tscore\tstrand\ttranscript\tstart\tend\trepeats_overlap\trepeats_dist\tphastcons_overlap\tphastcons_dist",
+            "13\t19305537\t19305538\t\t\t-\tENST00000400109.2\t87\t88\tTRUE\t0\tFALSE\t63",
+            "13\t19305614\t19305615\t\t\t-\tENST00000400109.2\t10\t11\tFALSE\t75\tFALSE\t5",
+        ]);
+    }
+}
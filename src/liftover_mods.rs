@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use log::warn;
+use crate::error::{R2dError, create_file, open_file};
+use crate::liftover::transcript_to_genomic_position;
+use crate::liftover_sam::{parse_cigar, reverse_complement};
+use crate::parse_gtf::{Transcript, read_annotation_file};
+
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// modified base of a read, as described by the MM and ML tags
+#[derive(Debug, PartialEq)]
+struct ModificationCall {
+    // index of the base in the original read orientation
+    read_index: usize,
+    code: String,
+    // the modification is called on the strand opposite to the read
+    opposite_strand: bool,
+    probability: Option<u8>,
+}
+
+fn complement_base(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        other => other,
+    }
+}
+
+// parse the MM and ML tags against the read sequence in its original orientation
+// e.g. MM:Z:A+a?,2,0; ML:B:C,230,12
+fn parse_base_modifications(mm: &str, ml: &[u8], sequence: &[u8]) -> Option<Vec<ModificationCall>> {
+    let mut calls = Vec::new();
+    let mut ml_values = ml.iter();
+
+    for entry in mm.split(';').filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.split(',');
+        let header = parts.next()?.as_bytes();
+        if header.len() < 3 {
+            return None;
+        }
+
+        let base = header[0].to_ascii_uppercase();
+        let opposite_strand = header[1] == b'-';
+        let codes = std::str::from_utf8(&header[2..]).ok()?.trim_end_matches(['.', '?']);
+
+        // numeric (ChEBI) codes describe a single modification, otherwise each letter is a modification
+        let codes: Vec<String> = if codes.chars().all(|c| c.is_ascii_digit()) {
+            vec![codes.to_string()]
+        } else {
+            codes.chars().map(|c| c.to_string()).collect()
+        };
+
+        // the skip counts refer to occurrences of the canonical base (its complement for the opposite strand)
+        let target = if opposite_strand { complement_base(base) } else { base };
+        let mut occurrences = sequence.iter().enumerate()
+            .filter(|(_, &read_base)| target == b'N' || read_base.to_ascii_uppercase() == target)
+            .map(|(index, _)| index);
+
+        for skip in parts {
+            let skip: usize = skip.trim().parse().ok()?;
+            let read_index = occurrences.nth(skip)?;
+            for code in &codes {
+                calls.push(ModificationCall {
+                    read_index,
+                    code: code.clone(),
+                    opposite_strand,
+                    probability: ml_values.next().copied(),
+                });
+            }
+        }
+    }
+
+    Some(calls)
+}
+
+// 0-based reference position of each stored read base, None for inserted and clipped bases
+fn reference_positions(ref_start: u64, cigar: &[(u64, char)], read_length: usize) -> Vec<Option<u64>> {
+    let mut positions = Vec::with_capacity(read_length);
+    let mut ref_pos = ref_start;
+
+    for &(length, op) in cigar {
+        match op {
+            'M' | '=' | 'X' => {
+                positions.extend((ref_pos..ref_pos + length).map(Some));
+                ref_pos += length;
+            }
+            'I' | 'S' => positions.extend((0..length).map(|_| None)),
+            'D' | 'N' => ref_pos += length,
+            _ => {}
+        }
+    }
+
+    positions
+}
+
+// MM skip counts run along the full read as sequenced, but secondary and supplementary records usually store
+// a truncated sequence behind a hard clip, so their calls can't be placed
+fn has_partial_sequence(fields: &[&str]) -> bool {
+    let flag: u16 = fields[1].parse().unwrap_or(0);
+    flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 || fields[5].contains('H')
+}
+
+// project the modification calls of a single SAM record onto the genome
+// returns one output row per modified base: read_id, chrom, pos, strand, mod code and probability
+fn project_modifications(fields: &[&str], transcripts: &HashMap<String, Transcript>, has_version: bool) -> Option<Vec<String>> {
+    let flag: u16 = fields[1].parse().ok()?;
+    if flag & FLAG_UNMAPPED != 0 || fields[9] == "*" {
+        return None;
+    }
+
+    let mm = fields[11..].iter().find_map(|tag| tag.strip_prefix("MM:Z:").or_else(|| tag.strip_prefix("Mm:Z:")))?;
+    let ml: Vec<u8> = fields[11..].iter()
+        .find_map(|tag| tag.strip_prefix("ML:B:C,").or_else(|| tag.strip_prefix("Ml:B:C,")))
+        .map(|values| values.split(',').filter_map(|value| value.parse().ok()).collect())
+        .unwrap_or_default();
+
+    let transcript_id = if has_version { fields[2] } else { fields[2].split('.').next()? };
+    let transcript = transcripts.get(transcript_id)?;
+
+    // MM positions are counted along the read as sequenced, which is reversed for reverse strand alignments
+    let is_reverse = flag & FLAG_REVERSE != 0;
+    let stored_sequence = fields[9];
+    let original_sequence = if is_reverse { reverse_complement(stored_sequence) } else { stored_sequence.to_string() };
+    let calls = parse_base_modifications(mm, &ml, original_sequence.as_bytes())?;
+
+    let ref_start = fields[3].parse::<u64>().ok()?.checked_sub(1)?;
+    let positions = reference_positions(ref_start, &parse_cigar(fields[5])?, stored_sequence.len());
+    let read_length = stored_sequence.len();
+
+    let rows = calls.iter().filter_map(|call| {
+        let stored_index = if is_reverse { read_length - 1 - call.read_index } else { call.read_index };
+        let tx_position = (*positions.get(stored_index)?)?;
+        let genomic_position = transcript_to_genomic_position(transcript, tx_position)?;
+
+        // strand of the modified base on the genome
+        let on_transcript_strand = is_reverse == call.opposite_strand;
+        let strand = match (transcript.strand.as_deref(), on_transcript_strand) {
+            (Some("+"), true) | (Some("-"), false) => "+",
+            (Some("-"), true) | (Some("+"), false) => "-",
+            _ => ".",
+        };
+        // ML values encode the probability range [N/256, (N+1)/256)
+        let probability = call.probability.map_or("NA".to_string(), |p| format!("{:.4}", (p as f64 + 0.5) / 256.0));
+
+        Some(format!("{}\t{}\t{}\t{}\t{}\t{}", fields[0], transcript.chromosome, genomic_position, strand, call.code, probability))
+    }).collect();
+
+    Some(rows)
+}
+
+pub fn run_liftover_mods(matches: &clap::ArgMatches, has_version: bool) -> Result<(), R2dError> {
+    let gtf_file: String = matches.get_one::<String>("gtf").unwrap().to_string();
+    let input_file: String = matches.get_one::<String>("input").unwrap().to_string();
+    let output_file: Option<String> = matches.get_one::<String>("output").map(|s: &String| s.to_string());
+
+    let transcripts = read_annotation_file(&gtf_file, true, has_version)?;
+
+    let input_reader = BufReader::with_capacity(512 * 1024, open_file(&input_file)?);
+    let mut output_writer = BufWriter::with_capacity(512 * 1024, match output_file {
+        Some(file_name) => Box::new(create_file(&file_name)?) as Box<dyn Write>,
+        None => Box::new(std::io::stdout()) as Box<dyn Write>,
+    });
+
+    writeln!(output_writer, "read_id\tchrom\tpos\tstrand\tmod_code\tprobability")?;
+
+    let mut projected = 0;
+    let mut skipped = 0;
+    let mut partial = 0;
+    for line in input_reader.lines() {
+        let line = line?;
+        if line.starts_with('@') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 11 {
+            skipped += 1;
+            continue;
+        }
+
+        if has_partial_sequence(&fields) {
+            partial += 1;
+            continue;
+        }
+
+        match project_modifications(&fields, &transcripts, has_version) {
+            Some(rows) if !rows.is_empty() => {
+                for row in rows {
+                    writeln!(output_writer, "{}", row)?;
+                }
+                projected += 1;
+            }
+            _ => skipped += 1,
+        }
+    }
+
+    if partial > 0 {
+        warn!("{} secondary, supplementary or hard-clipped SAM records were skipped, their sequence does not cover the whole read", partial);
+    }
+    if skipped > 0 {
+        warn!("{} SAM records without mapped modification calls were skipped", skipped);
+    }
+    if projected == 0 && skipped + partial > 0 {
+        output_writer.flush()?;
+        return Err(R2dError::Mapping(format!(
+            "none of the {} SAM records in {} had modification calls that could be projected onto the genome, check that the GTF matches the transcriptome",
+            skipped + partial, input_file
+        )));
+    }
+
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_gtf::Exon;
+
+    fn create_test_transcripts(strand: &str) -> HashMap<String, Transcript> {
+        let transcript = Transcript {
+            transcript_id: "tx1".to_string(),
+            strand: Some(strand.to_string()),
+            chromosome: "chr1".to_string(),
+            exons: vec![(101, 110), (201, 210)].into_iter().map(|(start, end)| Exon {
+                start,
+                end,
+                length: end - start + 1,
+                feature: Some("exon".to_string()),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        };
+        let mut transcripts = HashMap::new();
+        transcripts.insert("tx1".to_string(), transcript);
+        transcripts
+    }
+
+    #[test]
+    fn test_parse_base_modifications() {
+        // A at indices 0, 2, 5; the first and third are modified
+        let calls = parse_base_modifications("A+a?,0,1;", &[250, 10], b"ACAGGA").unwrap();
+        assert_eq!(calls, vec![
+            ModificationCall { read_index: 0, code: "a".to_string(), opposite_strand: false, probability: Some(250) },
+            ModificationCall { read_index: 5, code: "a".to_string(), opposite_strand: false, probability: Some(10) },
+        ]);
+
+        // two codes share the positions, and ML values are ordered per position
+        let calls = parse_base_modifications("C+mh,1", &[200, 20], b"CACG").unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[0].read_index, calls[0].code.as_str(), calls[0].probability), (2, "m", Some(200)));
+        assert_eq!((calls[1].read_index, calls[1].code.as_str(), calls[1].probability), (2, "h", Some(20)));
+
+        // ChEBI codes and skip counts beyond the read
+        let calls = parse_base_modifications("T+17802,0", &[], b"AT").unwrap();
+        assert_eq!(calls[0].code, "17802");
+        assert_eq!(calls[0].probability, None);
+        assert_eq!(parse_base_modifications("A+a,3", &[], b"AA"), None);
+    }
+
+    #[test]
+    fn test_reference_positions() {
+        let cigar = parse_cigar("1S2M1I1D2M").unwrap();
+        assert_eq!(reference_positions(10, &cigar, 6), vec![None, Some(10), Some(11), None, Some(13), Some(14)]);
+    }
+
+    #[test]
+    fn test_project_modifications_across_junction() {
+        let transcripts = create_test_transcripts("+");
+        // read aligned at transcript positions 8-11, crossing the junction between 110 and 201
+        let line = "read1\t0\ttx1\t9\t60\t4M\t*\t0\t0\tAGAA\t*\tMM:Z:A+a,0,1\tML:B:C,255,0";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let rows = project_modifications(&fields, &transcripts, false).unwrap();
+        assert_eq!(rows, vec![
+            "read1\tchr1\t108\t+\ta\t0.9980".to_string(),
+            "read1\tchr1\t201\t+\ta\t0.0020".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_project_modifications_minus_strand_transcript() {
+        let transcripts = create_test_transcripts("-");
+        // transcript position 0 is genomic 210 (0-based 209)
+        let line = "read1\t0\ttx1\t1\t60\t2M\t*\t0\t0\tGA\t*\tMM:Z:A+a,0\tML:B:C,128";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let rows = project_modifications(&fields, &transcripts, false).unwrap();
+        assert_eq!(rows, vec!["read1\tchr1\t208\t-\ta\t0.5020".to_string()]);
+    }
+
+    #[test]
+    fn test_project_modifications_with_utr_features() {
+        // UTR features overlapping the exons are not part of the transcript sequence
+        let mut transcripts = create_test_transcripts("+");
+        let transcript = transcripts.get_mut("tx1").unwrap();
+        for (start, end) in [(101, 105), (206, 210)] {
+            transcript.exons.push(Exon { start, end, length: end - start + 1, feature: Some("UTR".to_string()), ..Default::default() });
+        }
+        let line = "read1\t0\ttx1\t9\t60\t4M\t*\t0\t0\tAGAA\t*\tMM:Z:A+a,0,1\tML:B:C,255,0";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let rows = project_modifications(&fields, &transcripts, false).unwrap();
+        assert_eq!(rows, vec![
+            "read1\tchr1\t108\t+\ta\t0.9980".to_string(),
+            "read1\tchr1\t201\t+\ta\t0.0020".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_run_liftover_mods_skips_partial_sequences() {
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n";
+        // the supplementary and hard-clipped records store only part of the read, so the MM skip counts
+        // of the full read can't be placed on their bases
+        let sam_data = "@HD\tVN:1.6\n\
+read1\t0\tT1\t1\t60\t4M\t*\t0\t0\tACAA\t*\tMM:Z:A+a,0\tML:B:C,128\n\
+read1\t2048\tT1\t11\t60\t2H2M\t*\t0\t0\tAA\t*\tMM:Z:A+a,0\tML:B:C,128\n\
+read2\t0\tT1\t21\t60\t1H3M\t*\t0\t0\tCAA\t*\tMM:Z:A+a,0\tML:B:C,128\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let sam_path = temp_dir.path().join("input.sam");
+        let output_path = temp_dir.path().join("output.tsv");
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&sam_path, sam_data).unwrap();
+
+        let matches = clap::Command::new("test")
+            .arg(clap::Arg::new("gtf").short('g').long("gtf").required(true))
+            .arg(clap::Arg::new("input").short('i').long("input").required(true))
+            .arg(clap::Arg::new("output").short('o').long("output"))
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", sam_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+            ]);
+
+        run_liftover_mods(&matches, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output_path).unwrap(),
+            "read_id\tchrom\tpos\tstrand\tmod_code\tprobability\nread1\t1\t100\t+\ta\t0.5020\n"
+        );
+    }
+
+    #[test]
+    fn test_run_liftover_mods_without_projected_records() {
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n";
+        let sam_data = "@HD\tVN:1.6\n\
+read1\t0\tT2\t1\t60\t2M\t*\t0\t0\tGA\t*\tMM:Z:A+a,0\tML:B:C,128\n\
+read2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let sam_path = temp_dir.path().join("input.sam");
+        let output_path = temp_dir.path().join("output.tsv");
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&sam_path, sam_data).unwrap();
+
+        let matches = clap::Command::new("test")
+            .arg(clap::Arg::new("gtf").short('g').long("gtf").required(true))
+            .arg(clap::Arg::new("input").short('i').long("input").required(true))
+            .arg(clap::Arg::new("output").short('o').long("output"))
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", sam_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+            ]);
+
+        let error = run_liftover_mods(&matches, false).err().unwrap();
+        assert!(matches!(error, R2dError::Mapping(_)));
+        assert_eq!(std::fs::read_to_string(output_path).unwrap(), "read_id\tchrom\tpos\tstrand\tmod_code\tprobability\n");
+    }
+
+    #[test]
+    fn test_project_modifications_reverse_read() {
+        let transcripts = create_test_transcripts("+");
+        // the read was sequenced as TTC, so the T call is on the first base of the original read (stored last)
+        let line = "read1\t16\ttx1\t1\t60\t3M\t*\t0\t0\tGAA\t*\tMM:Z:T+a,0\tML:B:C,255";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let rows = project_modifications(&fields, &transcripts, false).unwrap();
+        assert_eq!(rows, vec!["read1\tchr1\t102\t-\ta\t0.9980".to_string()]);
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use log::warn;
+use crate::error::{R2dError, create_file, open_file};
+use crate::parse_gtf::{Exon, Transcript, read_annotation_file};
+
+// SAM flags rewritten when projecting a read onto the genome
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_MATE_REVERSE: u16 = 0x20;
+
+// tags that refer to reference bases or other alignments, and are no longer valid after projection
+const DROPPED_TAGS: [&str; 2] = ["SA:", "XS:"];
+
+// exon of a transcript, with the transcript coordinate of its 5' end
+struct ExonBlock {
+    tx_start: u64,
+    length: u64,
+    start: u64,
+    end: u64,
+}
+
+// genomic alignment of a transcriptome-aligned read
+#[derive(Debug, PartialEq)]
+struct GenomicAlignment {
+    position: u64,
+    cigar: String,
+    reverse: bool,
+}
+
+pub fn parse_cigar(cigar: &str) -> Option<Vec<(u64, char)>> {
+    let mut ops = Vec::new();
+    let mut length = String::new();
+    for c in cigar.chars() {
+        if c.is_ascii_digit() {
+            length.push(c);
+        } else {
+            ops.push((length.parse().ok()?, c));
+            length.clear();
+        }
+    }
+    if !length.is_empty() {
+        return None;
+    }
+    Some(ops)
+}
+
+fn format_cigar(ops: &[(u64, char)]) -> String {
+    ops.iter().map(|(length, op)| format!("{}{}", length, op)).collect()
+}
+
+// append an operation, merging it with the previous one when they are the same
+fn push_op(ops: &mut Vec<(u64, char)>, length: u64, op: char) {
+    match ops.last_mut() {
+        Some((last_length, last_op)) if *last_op == op => *last_length += length,
+        _ => ops.push((length, op)),
+    }
+}
+
+fn exon_blocks(exons: &[&Exon]) -> Vec<ExonBlock> {
+    let mut tx_start = 0;
+    exons.iter().map(|exon| {
+        let block = ExonBlock { tx_start, length: exon.length, start: exon.start, end: exon.end };
+        tx_start += exon.length;
+        block
+    }).collect()
+}
+
+// project an alignment starting at the 0-based transcript position tx_start through the exon structure
+// reference-consuming operations that cross an exon boundary are split with an N operation spanning the intron
+fn project_alignment(transcript: &Transcript, tx_start: u64, cigar: &[(u64, char)]) -> Option<GenomicAlignment> {
+    let blocks = exon_blocks(&transcript.ordered_exons());
+    let is_minus = transcript.strand.as_deref() == Some("-");
+
+    // None when the exons overlap, so the record is skipped instead of spanning a negative intron
+    let intron_length = |from: &ExonBlock, to: &ExonBlock| {
+        let (upstream_end, downstream_start) = if is_minus { (to.end, from.start) } else { (from.end, to.start) };
+        let length = downstream_start.checked_sub(upstream_end + 1);
+        if length.is_none() {
+            warn!(
+                "Skipping alignment on {}: exons {}-{} and {}-{} overlap",
+                transcript.transcript_id, from.start, from.end, to.start, to.end
+            );
+        }
+        length
+    };
+    let find_block = |tx_pos: u64| blocks.iter().position(|block| tx_pos >= block.tx_start && tx_pos < block.tx_start + block.length);
+
+    let mut ops: Vec<(u64, char)> = Vec::new();
+    let mut tx_pos = tx_start;
+    let mut current_block: Option<usize> = None;
+
+    for &(length, op) in cigar {
+        if !matches!(op, 'M' | '=' | 'X' | 'D' | 'N') {
+            push_op(&mut ops, length, op);
+            continue;
+        }
+
+        let mut remaining = length;
+        while remaining > 0 {
+            let block_index = find_block(tx_pos)?;
+            if let Some(previous) = current_block {
+                for i in previous..block_index {
+                    // abutting exons leave no intron to skip
+                    match intron_length(&blocks[i], &blocks[i + 1])? {
+                        0 => {}
+                        length => push_op(&mut ops, length, 'N'),
+                    }
+                }
+            }
+            current_block = Some(block_index);
+
+            let block = &blocks[block_index];
+            let taken = remaining.min(block.tx_start + block.length - tx_pos);
+            push_op(&mut ops, taken, op);
+            tx_pos += taken;
+            remaining -= taken;
+        }
+    }
+
+    // alignments without reference-consuming operations can't be placed
+    let first_block = &blocks[find_block(tx_start)?];
+    let last_block = &blocks[current_block?];
+    let tx_last = tx_pos - 1;
+
+    // 1-based leftmost genomic position of the alignment
+    let position = if is_minus {
+        ops.reverse();
+        last_block.end - (tx_last - last_block.tx_start)
+    } else {
+        first_block.start + (tx_start - first_block.tx_start)
+    };
+
+    Some(GenomicAlignment { position, cigar: format_cigar(&ops), reverse: is_minus })
+}
+
+pub fn reverse_complement(sequence: &str) -> String {
+    sequence.chars().rev().map(|base| match base {
+        'A' => 'T',
+        'T' | 'U' => 'A',
+        'C' => 'G',
+        'G' => 'C',
+        'a' => 't',
+        't' | 'u' => 'a',
+        'c' => 'g',
+        'g' => 'c',
+        other => other,
+    }).collect()
+}
+
+// rewrite a single transcriptome SAM record in genome coordinates
+fn project_record(fields: &[&str], transcripts: &HashMap<String, Transcript>, has_version: bool) -> Option<String> {
+    let flag: u16 = fields[1].parse().ok()?;
+    if flag & FLAG_UNMAPPED != 0 {
+        return None;
+    }
+
+    let transcript_id = if has_version { fields[2] } else { fields[2].split('.').next()? };
+    let transcript = transcripts.get(transcript_id)?;
+    let tx_start = fields[3].parse::<u64>().ok()?.checked_sub(1)?;
+    let cigar = parse_cigar(fields[5])?;
+    let alignment = project_alignment(transcript, tx_start, &cigar)?;
+
+    let mut flag = flag & !FLAG_MATE_REVERSE;
+    let (mut sequence, mut quality) = (fields[9].to_string(), fields[10].to_string());
+    if alignment.reverse {
+        flag ^= FLAG_REVERSE;
+        if sequence != "*" {
+            sequence = reverse_complement(&sequence);
+        }
+        if quality != "*" {
+            quality = quality.chars().rev().collect();
+        }
+    }
+
+    let mut record = vec![
+        fields[0].to_string(),
+        flag.to_string(),
+        transcript.chromosome.clone(),
+        alignment.position.to_string(),
+        fields[4].to_string(),
+        alignment.cigar,
+        // mate information refers to transcript coordinates and is reset
+        "*".to_string(),
+        "0".to_string(),
+        "0".to_string(),
+        sequence,
+        quality,
+    ];
+
+    // MD describes the reference in transcript orientation, and is only kept for forward strand transcripts
+    record.extend(fields[11..].iter()
+        .filter(|tag| !DROPPED_TAGS.iter().any(|dropped| tag.starts_with(dropped)))
+        .filter(|tag| !(alignment.reverse && tag.starts_with("MD:")))
+        .map(|tag| tag.to_string()));
+    record.push(format!("XS:A:{}", transcript.strand.as_deref().unwrap_or(".")));
+
+    Some(record.join("\t"))
+}
+
+// chromosome lengths for the @SQ header lines, from a chrom.sizes or fasta index (.fai) file
+// without such a file, the largest annotated coordinate of each chromosome is used
+fn chromosome_lengths(transcripts: &HashMap<String, Transcript>, sizes_file: Option<&String>) -> Result<BTreeMap<String, u64>, R2dError> {
+    let mut lengths = BTreeMap::new();
+
+    match sizes_file {
+        Some(file_path) => {
+            for line in BufReader::new(open_file(file_path)?).lines() {
+                let line = line?;
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() >= 2 {
+                    let length = fields[1].trim().parse::<u64>()
+                        .map_err(|_| R2dError::AnnotationParse(format!("Invalid chromosome length '{}' in {}", fields[1], file_path)))?;
+                    lengths.insert(fields[0].to_string(), length);
+                }
+            }
+        }
+        None => {
+            warn!("No chromosome sizes provided, @SQ lengths are set to the largest annotated coordinate");
+            for transcript in transcripts.values() {
+                let end = transcript.exons.iter().map(|exon| exon.end).max().unwrap_or(0);
+                let length = lengths.entry(transcript.chromosome.clone()).or_insert(0);
+                *length = (*length).max(end);
+            }
+        }
+    }
+
+    Ok(lengths)
+}
+
+// @HD and the genome @SQ lines come first, then the remaining input header lines (@RG, @PG, @CO) and the r2d @PG line
+fn write_genome_header<W: Write>(writer: &mut W, lengths: &BTreeMap<String, u64>, has_hd: bool, header_lines: &[String]) -> std::io::Result<()> {
+    if has_hd {
+        writeln!(writer, "@HD\tVN:1.6\tSO:unsorted")?;
+    }
+    for (chromosome, length) in lengths {
+        writeln!(writer, "@SQ\tSN:{}\tLN:{}", chromosome, length)?;
+    }
+    for line in header_lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(writer, "@PG\tID:r2d-liftover-sam\tPN:r2d\tVN:{}", env!("CARGO_PKG_VERSION"))
+}
+
+pub fn run_liftover_sam(matches: &clap::ArgMatches, has_version: bool) -> Result<(), R2dError> {
+    let gtf_file: String = matches.get_one::<String>("gtf").unwrap().to_string();
+    let input_file: String = matches.get_one::<String>("input").unwrap().to_string();
+    let output_file: Option<String> = matches.get_one::<String>("output").map(|s: &String| s.to_string());
+
+    let transcripts = read_annotation_file(&gtf_file, true, has_version)?;
+    let lengths = chromosome_lengths(&transcripts, matches.get_one::<String>("chrom-sizes"))?;
+
+    let input_reader = BufReader::with_capacity(512 * 1024, open_file(&input_file)?);
+    let mut output_writer = BufWriter::with_capacity(512 * 1024, match output_file {
+        Some(file_name) => Box::new(create_file(&file_name)?) as Box<dyn Write>,
+        None => Box::new(std::io::stdout()) as Box<dyn Write>,
+    });
+
+    let mut header_written = false;
+    let mut has_hd = false;
+    let mut header_lines: Vec<String> = Vec::new();
+    let mut projected = 0;
+    let mut skipped = 0;
+
+    for line in input_reader.lines() {
+        let line = line?;
+
+        // rewrite the header: transcript @SQ lines are replaced with genome @SQ lines
+        if line.starts_with('@') {
+            if line.starts_with("@HD") {
+                has_hd = true;
+            } else if !line.starts_with("@SQ") {
+                header_lines.push(line);
+            }
+            continue;
+        }
+
+        if !header_written {
+            write_genome_header(&mut output_writer, &lengths, has_hd, &header_lines)?;
+            header_written = true;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 11 {
+            skipped += 1;
+            continue;
+        }
+
+        match project_record(&fields, &transcripts, has_version) {
+            Some(record) => {
+                writeln!(output_writer, "{}", record)?;
+                projected += 1;
+            }
+            None => skipped += 1,
+        }
+    }
+
+    if !header_written {
+        write_genome_header(&mut output_writer, &lengths, has_hd, &header_lines)?;
+    }
+
+    if skipped > 0 {
+        warn!("{} unmapped or unprojectable SAM records were skipped", skipped);
+    }
+    if projected == 0 && skipped > 0 {
+        output_writer.flush()?;
+        return Err(R2dError::Mapping(format!(
+            "none of the {} SAM records in {} could be projected onto the genome, check that the GTF matches the transcriptome",
+            skipped, input_file
+        )));
+    }
+
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_transcript(strand: &str, exons: Vec<(u64, u64)>) -> Transcript {
+        Transcript {
+            transcript_id: "tx1".to_string(),
+            strand: Some(strand.to_string()),
+            chromosome: "chr1".to_string(),
+            exons: exons.into_iter().map(|(start, end)| Exon {
+                start,
+                end,
+                length: end - start + 1,
+                feature: Some("exon".to_string()),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_cigar() {
+        assert_eq!(parse_cigar("5S10M2I3M"), Some(vec![(5, 'S'), (10, 'M'), (2, 'I'), (3, 'M')]));
+        assert_eq!(parse_cigar("10M5"), None);
+    }
+
+    #[test]
+    fn test_project_alignment_plus_strand() {
+        // exons 101-200 and 301-400, junction at transcript position 100
+        let transcript = create_test_transcript("+", vec![(101, 200), (301, 400)]);
+        let cigar = parse_cigar("2S20M1I10M").unwrap();
+        let alignment = project_alignment(&transcript, 90, &cigar).unwrap();
+        assert_eq!(alignment, GenomicAlignment { position: 191, cigar: "2S10M100N10M1I10M".to_string(), reverse: false });
+    }
+
+    #[test]
+    fn test_project_alignment_ending_at_junction() {
+        let transcript = create_test_transcript("+", vec![(101, 200), (301, 400)]);
+        let cigar = parse_cigar("10M2I10M").unwrap();
+        let alignment = project_alignment(&transcript, 90, &cigar).unwrap();
+        assert_eq!(alignment.cigar, "10M2I100N10M");
+    }
+
+    #[test]
+    fn test_project_alignment_minus_strand() {
+        // transcript position 0 is genomic 400; the junction at transcript position 100 joins 301 to 200
+        let transcript = create_test_transcript("-", vec![(101, 200), (301, 400)]);
+        let cigar = parse_cigar("20M5S").unwrap();
+        let alignment = project_alignment(&transcript, 90, &cigar).unwrap();
+        assert_eq!(alignment, GenomicAlignment { position: 191, cigar: "5S10M100N10M".to_string(), reverse: true });
+    }
+
+    #[test]
+    fn test_project_alignment_abutting_and_overlapping_exons() {
+        // abutting exons are contiguous in the genome, so no N operation is added
+        let transcript = create_test_transcript("+", vec![(101, 200), (201, 300)]);
+        let cigar = parse_cigar("20M").unwrap();
+        assert_eq!(project_alignment(&transcript, 90, &cigar).unwrap().cigar, "20M");
+
+        // overlapping exons have no valid intron, the alignment is skipped
+        let transcript = create_test_transcript("+", vec![(101, 200), (151, 300)]);
+        assert_eq!(project_alignment(&transcript, 90, &cigar), None);
+        let transcript = create_test_transcript("-", vec![(101, 200), (151, 300)]);
+        assert_eq!(project_alignment(&transcript, 140, &cigar), None);
+    }
+
+    #[test]
+    fn test_project_alignment_out_of_bounds() {
+        let transcript = create_test_transcript("+", vec![(101, 200)]);
+        let cigar = parse_cigar("20M").unwrap();
+        assert_eq!(project_alignment(&transcript, 90, &cigar), None);
+    }
+
+    #[test]
+    fn test_project_record_minus_strand() {
+        let mut transcripts = HashMap::new();
+        transcripts.insert("tx1".to_string(), create_test_transcript("-", vec![(101, 200), (301, 400)]));
+
+        let line = "read1\t0\ttx1.2\t91\t60\t4M\t*\t0\t0\tACGG\tIIJK\tMD:Z:4\tNM:i:0";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let record = project_record(&fields, &transcripts, false).unwrap();
+        assert_eq!(record, "read1\t16\tchr1\t307\t60\t4M\t*\t0\t0\tCCGT\tKJII\tNM:i:0\tXS:A:-");
+    }
+
+    #[test]
+    fn test_run_liftover_sam() {
+        let gtf_data = "1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n\
+1\ttest\texon\t301\t400\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n";
+        let sam_data = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:T1.1\tLN:200\n@PG\tID:minimap2\tPN:minimap2\n\
+read1\t0\tT1.1\t91\t60\t20M\t*\t0\t0\t*\t*\tNM:i:0\n\
+read2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let gtf_path = temp_dir.path().join("test.gtf");
+        let sam_path = temp_dir.path().join("input.sam");
+        let sizes_path = temp_dir.path().join("chrom.sizes");
+        let output_path = temp_dir.path().join("output.sam");
+        std::fs::write(&gtf_path, gtf_data).unwrap();
+        std::fs::write(&sam_path, sam_data).unwrap();
+        std::fs::write(&sizes_path, "1\t1000\n").unwrap();
+
+        let matches = clap::Command::new("test")
+            .arg(clap::Arg::new("gtf").short('g').long("gtf").required(true))
+            .arg(clap::Arg::new("input").short('i').long("input").required(true))
+            .arg(clap::Arg::new("output").short('o').long("output"))
+            .arg(clap::Arg::new("chrom-sizes").short('s').long("chrom-sizes"))
+            .get_matches_from(vec![
+                "test",
+                "-g", gtf_path.to_str().unwrap(),
+                "-i", sam_path.to_str().unwrap(),
+                "-o", output_path.to_str().unwrap(),
+                "-s", sizes_path.to_str().unwrap(),
+            ]);
+
+        run_liftover_sam(&matches, false).unwrap();
+
+        let output = std::fs::read_to_string(output_path).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(lines[1], "@SQ\tSN:1\tLN:1000");
+        assert_eq!(lines[2], "@PG\tID:minimap2\tPN:minimap2");
+        assert!(lines[3].starts_with("@PG\tID:r2d-liftover-sam"));
+        assert_eq!(lines[4], "read1\t0\t1\t191\t60\t10M100N10M\t*\t0\t0\t*\t*\tNM:i:0\tXS:A:+");
+        assert_eq!(lines.len(), 5);
+    }
+}
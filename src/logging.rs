@@ -0,0 +1,62 @@
+use std::io::Write;
+use std::sync::OnceLock;
+use env_logger::fmt::Target;
+use log::LevelFilter;
+use crate::error::{R2dError, create_file};
+
+// path of the log file, when diagnostics are written there instead of stderr
+static LOG_FILE: OnceLock<String> = OnceLock::new();
+
+// verbosity of the global -v/-q flags: progress and warnings by default,
+// -v adds debug messages, -vv trace messages, and -q keeps errors only
+pub fn level_filter(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+// install the logger for all diagnostics, which go to stderr or the --log-file
+// stdout is left to data output, so that liftover and annotate can be piped
+pub fn init_logger(matches: &clap::ArgMatches) -> Result<(), R2dError> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level_filter(matches.get_count("verbose"), matches.get_flag("quiet")));
+
+    match matches.get_one::<String>("log-file") {
+        Some(file_path) => {
+            builder.target(Target::Pipe(Box::new(create_file(file_path)?)));
+            builder.format(|buf, record| writeln!(buf, "{} [{}] {}", buf.timestamp_seconds(), record.level(), record.args()));
+            let _ = LOG_FILE.set(file_path.clone());
+        }
+        None => {
+            builder.target(Target::Stderr);
+            builder.format(|buf, record| writeln!(buf, "[{}] {}", record.level(), record.args()));
+        }
+    }
+
+    // the logger can only be installed once per process
+    let _ = builder.try_init();
+    Ok(())
+}
+
+pub fn log_file() -> Option<&'static str> {
+    LOG_FILE.get().map(|file_path| file_path.as_str())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        assert_eq!(level_filter(0, false), LevelFilter::Info);
+        assert_eq!(level_filter(1, false), LevelFilter::Debug);
+        assert_eq!(level_filter(3, false), LevelFilter::Trace);
+        assert_eq!(level_filter(2, true), LevelFilter::Error);
+    }
+}
@@ -4,56 +4,107 @@ use std::process::Command as ProcessCommand;
 use std::env;
 use std::path::{Path,PathBuf};
 use path_absolutize::Absolutize;
+use crate::error::R2dError;
+use log::{error, info};
 
 // modules
 pub mod parse_annotation;
 pub mod annotate;
 pub mod liftover;
 pub mod parse_gtf;
+pub mod sequence;
+pub mod chain;
+pub mod input;
+pub mod liftover_sam;
+pub mod liftover_mods;
+pub mod error;
+pub mod logging;
+pub mod threads;
+pub mod bed;
+pub mod landmarks;
+pub mod tracks;
+pub mod isoforms;
 
 const RELATIVE_SCRIPT_PATH: &str = "../../scripts/";
 
-pub fn generate_r_plots(script_name: &str, args: &[String], script_dir: Option<&Path>) -> Result<(), String> {
+pub fn generate_r_plots(script_name: &str, args: &[String], script_dir: Option<&Path>) -> Result<(), R2dError> {
     let script_path = if let Some(dir) = script_dir {
         dir.join(script_name)
     } else {
-        let current_exe = env::current_exe().map_err(|e| format!("Failed to get current executable path: {}", e))?;
+        let current_exe = env::current_exe()?;
         let script_dir = current_exe.parent()
-            .ok_or("Failed to get parent directory of executable")?
+            .ok_or_else(|| R2dError::Plot("Failed to get parent directory of executable".to_string()))?
             .join(RELATIVE_SCRIPT_PATH);
         script_dir.join(script_name)
     };
 
-    println!("Executing R script: {:?}", script_path);
+    info!("Executing R script: {:?}", script_path);
 
     let output = ProcessCommand::new("Rscript")
         .arg(&script_path)
         .args(args)
         .output()
-        .map_err(|e| format!("Failed to execute R script: {}", e))?;
+        .map_err(|e| R2dError::Plot(format!("Failed to execute R script: {}", e)))?;
 
-    // Print stdout
-    println!("R script stdout:");
-    println!("{}", String::from_utf8_lossy(&output.stdout));
-
-    // Print stderr
-    eprintln!("R script stderr:");
-    eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    // R script output is reported as diagnostics, keeping stdout free
+    info!("R script stdout:\n{}", String::from_utf8_lossy(&output.stdout));
+    info!("R script stderr:\n{}", String::from_utf8_lossy(&output.stderr));
 
     if output.status.success() {
-        println!("R script ran successfully.");
+        info!("R script ran successfully.");
         Ok(())
     } else {
-        Err(format!("R script exited with non-zero status: {:?}", output.status))
+        Err(R2dError::Plot(format!("R script exited with non-zero status: {:?}", output.status)))
     }
 }
 
+// report the error of a failed subcommand and exit with the code of its class
+// the error is also printed to stderr when diagnostics go to a log file
+fn exit_with_error(command: &str, e: R2dError) -> ! {
+    error!("Error running {}: {}", command, e);
+    if let Some(log_file) = logging::log_file() {
+        eprintln!("Error running {}: {} (see {})", command, e, log_file);
+    }
+    std::process::exit(e.exit_code());
+}
+
 fn main() {
     let matches = Command::new("R2Dtool")
         .version("1.0.0")
         .author("AJ Sethi, compRNA <aditya.sethi@anu.edu.au; CompRNA@ANU365.onmicrosoft.com>")
         .about("R2Dtool")
         .arg_required_else_help(true) 
+        .arg(
+            Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Report more diagnostics: -v for debug and -vv for trace messages")
+            .action(clap::ArgAction::Count)
+            .global(true)
+        )
+        .arg(
+            Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .help("Only report errors")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("verbose")
+            .global(true)
+        )
+        .arg(
+            Arg::new("log-file")
+            .long("log-file")
+            .value_name("LOG_FILE")
+            .help("Write diagnostics to this file instead of stderr")
+            .global(true)
+        )
+        .arg(
+            Arg::new("threads")
+            .long("threads")
+            .value_name("N")
+            .help("Number of worker threads, falling back to the R2D_THREADS environment variable [default: all cores]")
+            .global(true)
+        )
         .subcommand(
             Command::new("liftover")
                 .about("Converts transcriptomic to genomic coordinates")
@@ -70,8 +121,7 @@ fn main() {
                     .short('i')
                     .long("input")
                     .value_name("INPUT_FILE")
-                    .help("Path to input file with transcriptomic coordinates")
-                    .required(true)
+                    .help("Path to input file with transcriptomic coordinates, or '-' to read from stdin [default: stdin]")
                 )
                 .arg(
                     Arg::new("header")
@@ -94,6 +144,67 @@ fn main() {
                     .value_name("OUTPUT_FILE")
                     .help("Path to output file")
                 )
+                .arg(
+                    Arg::new("chain")
+                    .short('c')
+                    .long("chain")
+                    .value_name("CHAIN_FILE")
+                    .help("UCSC chain file used to convert lifted sites into the coordinates of a second assembly")
+                )
+                .arg(
+                    Arg::new("unmapped")
+                    .short('u')
+                    .long("unmapped")
+                    .value_name("UNMAPPED_FILE")
+                    .help("Path to output file for sites that fail the chain conversion, with the reason in the last column")
+                    .requires("chain")
+                )
+                .arg(
+                    Arg::new("coord-base")
+                    .long("coord-base")
+                    .value_name("0|1")
+                    .help("Coordinate convention of the input position column: 0 (0-based, half-open; default) or 1 (1-based). When given, the convention is recorded in a final coord_base column")
+                    .value_parser(["0", "1"])
+                )
+                .arg(
+                    Arg::new("id-column")
+                    .long("id-column")
+                    .value_name("COLUMN")
+                    .help("Input column holding the transcript ID, as a 1-based index or a header name (default: 1)")
+                )
+                .arg(
+                    Arg::new("pos-column")
+                    .long("pos-column")
+                    .value_name("COLUMN")
+                    .help("Input column holding the transcript position, as a 1-based index or a header name (default: 2)")
+                )
+                .arg(
+                    Arg::new("delimiter")
+                    .long("delimiter")
+                    .value_name("DELIMITER")
+                    .help("Input column delimiter: tab (default), comma, space or any single character")
+                )
+                .arg(
+                    Arg::new("input-format")
+                    .long("input-format")
+                    .value_name("FORMAT")
+                    .help("Format of the input sites: bed (default), or the site tables of cheui (model II), m6anet (data.site_proba.csv), xpore (diffmod), modkit (pileup bedMethyl) or epinano (per-site error). Caller tables are read with their own header and rewritten as transcript, start and end followed by the remaining columns")
+                    .value_parser(["bed", "cheui", "m6anet", "xpore", "modkit", "epinano"])
+                    .conflicts_with_all(["id-column", "pos-column", "delimiter"])
+                )
+                .arg(
+                    Arg::new("strict")
+                    .long("strict")
+                    .help("Stop with an error at the first malformed input row, instead of skipping malformed rows and reporting how many were skipped")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("track")
+                    .long("track")
+                    .value_name("[NAME=]BED")
+                    .help("BED file of genomic features (e.g. repeats, conserved elements, peaks); reports whether each site overlaps a feature and its distance to the nearest one. May be given several times")
+                    .action(clap::ArgAction::Append)
+                )
                 // .arg(
                 //     Arg::new("format")
                 //     .short('f')
@@ -102,6 +213,81 @@ fn main() {
                 //     .help("Specify the gene strutcture annotation format: gtf or gff (default: gff)")
                 // )
         )
+        .subcommand(
+            Command::new("liftover-sam")
+                .about("Converts transcriptome-aligned SAM records to spliced genomic alignments")
+                .arg(
+                    Arg::new("gtf")
+                    .short('g')
+                    .long("gtf")
+                    .value_name("GTF_FILE")
+                    .help("Path to GTF gene structure annotation")
+                    .required(true)
+                )
+                .arg(
+                    Arg::new("input")
+                    .short('i')
+                    .long("input")
+                    .value_name("SAM_FILE")
+                    .help("Path to SAM file aligned to the transcriptome")
+                    .required(true)
+                )
+                .arg(
+                    Arg::new("transcript-version")
+                    .short('t')
+                    .long("transcript-version")
+                    .help("Retain transcript version information (. delimited) in the SAM reference names")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("chrom-sizes")
+                    .short('s')
+                    .long("chrom-sizes")
+                    .value_name("SIZES_FILE")
+                    .help("Chromosome sizes (chrom.sizes or .fai) used for the @SQ header lines")
+                )
+                .arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("OUTPUT_FILE")
+                    .help("Path to output SAM file")
+                )
+        )
+        .subcommand(
+            Command::new("liftover-mods")
+                .about("Converts per-read modification calls (MM/ML tags) of transcriptome-aligned SAM records to genomic coordinates")
+                .arg(
+                    Arg::new("gtf")
+                    .short('g')
+                    .long("gtf")
+                    .value_name("GTF_FILE")
+                    .help("Path to GTF gene structure annotation")
+                    .required(true)
+                )
+                .arg(
+                    Arg::new("input")
+                    .short('i')
+                    .long("input")
+                    .value_name("SAM_FILE")
+                    .help("Path to SAM file aligned to the transcriptome, with MM and ML tags")
+                    .required(true)
+                )
+                .arg(
+                    Arg::new("transcript-version")
+                    .short('t')
+                    .long("transcript-version")
+                    .help("Retain transcript version information (. delimited) in the SAM reference names")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("OUTPUT_FILE")
+                    .help("Path to output file")
+                )
+        )
         .subcommand(
             Command::new("annotate")
                 .about("Annotates transcriptomic sites with genomic cooridnates")
@@ -118,8 +304,7 @@ fn main() {
                     .short('i')
                     .long("input")
                     .value_name("INPUT_FILE")
-                    .help("Path to input file with transcriptomic coordinates")
-                    .required(true)
+                    .help("Path to input file with transcriptomic coordinates, or '-' to read from stdin [default: stdin]")
                 )
                 .arg(
                     Arg::new("header")
@@ -142,6 +327,153 @@ fn main() {
                     .value_name("OUTPUT_FILE")
                     .help("Path to output file")
                 )
+                .arg(
+                    Arg::new("fasta")
+                    .short('f')
+                    .long("fasta")
+                    .value_name("FASTA_FILE")
+                    .help("Path to transcriptome FASTA, used to report the sequence context of each site")
+                    .conflicts_with("genome")
+                )
+                .arg(
+                    Arg::new("genome")
+                    .long("genome")
+                    .value_name("FASTA_FILE")
+                    .help("Path to genome FASTA, indexed with samtools faidx, spliced through the GTF exon structure to report the sequence context of each site")
+                )
+                .arg(
+                    Arg::new("context")
+                    .short('c')
+                    .long("context")
+                    .value_name("N")
+                    .help("Number of nucleotides reported either side of the site when a FASTA is provided")
+                    .default_value("10")
+                )
+                .arg(
+                    Arg::new("motif")
+                    .short('m')
+                    .long("motif")
+                    .value_name("IUPAC_MOTIF")
+                    .help("Report whether each site lies in the given IUPAC motif (e.g. DRACH); requires --fasta or --genome")
+                    .requires("sequence")
+                )
+                .arg(
+                    Arg::new("motif-offset")
+                    .long("motif-offset")
+                    .value_name("N")
+                    .help("0-based position of the site within the motif (default: central base of the motif)")
+                    .requires("motif")
+                )
+                .arg(
+                    Arg::new("coord-base")
+                    .long("coord-base")
+                    .value_name("0|1")
+                    .help("Coordinate convention of the input position column: 0 (0-based, half-open; default) or 1 (1-based). When given, the convention is recorded in a final coord_base column")
+                    .value_parser(["0", "1"])
+                )
+                .arg(
+                    Arg::new("id-column")
+                    .long("id-column")
+                    .value_name("COLUMN")
+                    .help("Input column holding the transcript ID, as a 1-based index or a header name (default: 1)")
+                )
+                .arg(
+                    Arg::new("pos-column")
+                    .long("pos-column")
+                    .value_name("COLUMN")
+                    .help("Input column holding the transcript position, as a 1-based index or a header name (default: 2)")
+                )
+                .arg(
+                    Arg::new("delimiter")
+                    .long("delimiter")
+                    .value_name("DELIMITER")
+                    .help("Input column delimiter: tab (default), comma, space or any single character")
+                )
+                .arg(
+                    Arg::new("input-format")
+                    .long("input-format")
+                    .value_name("FORMAT")
+                    .help("Format of the input sites: bed (default), or the site tables of cheui (model II), m6anet (data.site_proba.csv), xpore (diffmod), modkit (pileup bedMethyl) or epinano (per-site error). Caller tables are read with their own header and rewritten as transcript, start and end followed by the remaining columns")
+                    .value_parser(["bed", "cheui", "m6anet", "xpore", "modkit", "epinano"])
+                    .conflicts_with_all(["id-column", "pos-column", "delimiter"])
+                )
+                .arg(
+                    Arg::new("strict")
+                    .long("strict")
+                    .help("Stop with an error at the first malformed input row, instead of skipping malformed rows and reporting how many were skipped")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("genomic")
+                    .long("genomic")
+                    .help("Add the genomic chromosome, position and strand of each site; the position follows the coordinate convention of the input, so it matches liftover for 0-based sites")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("all-noncoding-metacoordinates")
+                    .long("all-noncoding-metacoordinates")
+                    .help("Also report noncoding_metacoordinate for coding transcripts, so that both classes can be compared in one metaplot")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("zero-length-segments")
+                    .long("zero-length-segments")
+                    .value_name("POLICY")
+                    .help("Handling of sites in a 5'UTR, CDS or 3'UTR of zero length: report an NA metacoordinate, clamp it to the segment boundary, or drop the site [default: na]")
+                    .value_parser(["na", "clamp", "drop"])
+                )
+                .arg(
+                    Arg::new("landmarks")
+                    .long("landmarks")
+                    .value_name("[NAME=]BED")
+                    .help("BED file of landmarks in transcript coordinates; reports the signed distance to the nearest landmark and its name. May be given several times")
+                    .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    Arg::new("track")
+                    .long("track")
+                    .value_name("[NAME=]BED")
+                    .help("BED file of genomic features (e.g. repeats, conserved elements, peaks); reports whether each site overlaps a feature and its distance to the nearest one. May be given several times")
+                    .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    Arg::new("anchor")
+                    .long("anchor")
+                    .value_name("ANCHOR")
+                    .help("Position of interval sites (e.g. peaks or windows) used for their annotation: the start, the last base (end) or the central base (midpoint) of the interval [default: start]")
+                    .value_parser(["start", "end", "midpoint"])
+                )
+                .arg(
+                    Arg::new("interval-composition")
+                    .long("interval-composition")
+                    .help("Report the length of interval sites, their bases in the 5'UTR, CDS and 3'UTR, and whether they span an exon-exon junction")
+                    .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("genomic-input")
+                    .long("genomic-input")
+                    .help("Read sites in genome coordinates (chromosome, start, end, ..., strand in column 6) and annotate them against every overlapping isoform, adding transcript_id and tx_coord columns")
+                    .action(clap::ArgAction::SetTrue)
+                    .conflicts_with_all(["anchor", "interval-composition"])
+                )
+                .arg(
+                    Arg::new("isoforms")
+                    .long("isoforms")
+                    .value_name("POLICY")
+                    .help("Isoforms reported for each genomic site: all overlapping isoforms, only those tagged as canonical (Ensembl_canonical or MANE_Select), or the best one, preferring canonical, then protein-coding, then longer isoforms [default: all]")
+                    .value_parser(["all", "canonical", "best"])
+                    .requires("genomic-input")
+                )
+                .arg(
+                    Arg::new("dump-junctions")
+                    .long("dump-junctions")
+                    .value_name("FILE")
+                    .help("Write the exon-exon junctions of each transcript used for the junction distances to a TSV file")
+                )
+                .group(
+                    clap::ArgGroup::new("sequence")
+                        .args(["fasta", "genome"])
+                )
                 // .arg(
                 //     Arg::new("format")
                 //     .short('f')
@@ -341,15 +673,47 @@ fn main() {
         )
         .get_matches();
 
+    // global options are propagated to the matches of the subcommand
+    let global_matches = matches.subcommand().map_or(&matches, |(_, subcommand_matches)| subcommand_matches);
+    if let Err(e) = logging::init_logger(global_matches) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+    if let Err(e) = threads::init_thread_pool(global_matches) {
+        exit_with_error("r2d", e);
+    }
+
     // Liftover 
     if let Some(liftover_matches) = matches.subcommand_matches("liftover") {
         let has_header = liftover_matches.get_flag("header");
         let has_version = liftover_matches.get_flag("transcript-version");
         
-        eprintln!("Running liftover...");
+        info!("Running liftover...");
 
         if let Err(e) = liftover::run_liftover(liftover_matches, has_header, has_version) {
-            eprintln!("Error running liftover: {}", e);
+            exit_with_error("liftover", e);
+        }
+    }
+
+    // Liftover SAM
+    if let Some(liftover_sam_matches) = matches.subcommand_matches("liftover-sam") {
+        let has_version = liftover_sam_matches.get_flag("transcript-version");
+
+        info!("Running liftover-sam...");
+
+        if let Err(e) = liftover_sam::run_liftover_sam(liftover_sam_matches, has_version) {
+            exit_with_error("liftover-sam", e);
+        }
+    }
+
+    // Liftover per-read modifications
+    if let Some(liftover_mods_matches) = matches.subcommand_matches("liftover-mods") {
+        let has_version = liftover_mods_matches.get_flag("transcript-version");
+
+        info!("Running liftover-mods...");
+
+        if let Err(e) = liftover_mods::run_liftover_mods(liftover_mods_matches, has_version) {
+            exit_with_error("liftover-mods", e);
         }
     }
 
@@ -358,10 +722,10 @@ fn main() {
         let has_header = annotate_matches.get_flag("header");
         let has_version = annotate_matches.get_flag("transcript-version");
 
-        eprintln!("Running annotate...");
+        info!("Running annotate...");
         
         if let Err(e) = annotate::run_annotate(annotate_matches, has_header, has_version) {
-            eprintln!("Error running annotate: {}", e);
+            exit_with_error("annotate", e);
         }
     }
 
@@ -385,15 +749,13 @@ fn main() {
             args.push("-l".to_string());
         }
 
-        println!("Arguments being passed to R2_plotMetaTranscript.R:");
-        println!("Rscript R2_plotMetaTranscript.R {}", args.join(" "));
+        info!("Arguments being passed to R2_plotMetaTranscript.R: Rscript R2_plotMetaTranscript.R {}", args.join(" "));
 
         match generate_r_plots("R2_plotMetaTranscript.R", &args, None) {
-            Ok(_) => println!("PlotMetaTranscript generated successfully."),
+            Ok(_) => info!("PlotMetaTranscript generated successfully."),
             Err(e) => {
-                eprintln!("Error: {}", e);
-                eprintln!("PlotMetaTranscript generation failed. Please check the error messages above.");
-                std::process::exit(1);
+                error!("PlotMetaTranscript generation failed. Please check the error messages above.");
+                exit_with_error("plotMetaTranscript", e);
             }
         }
     }
@@ -418,15 +780,13 @@ fn main() {
             args.push("-r".to_string());
         }
     
-        println!("Arguments being passed to R2_plotMetaJunction.R:");
-        println!("Rscript R2_plotMetaJunction.R {}", args.join(" "));
+        info!("Arguments being passed to R2_plotMetaJunction.R: Rscript R2_plotMetaJunction.R {}", args.join(" "));
     
         match generate_r_plots("R2_plotMetaJunction.R", &args, None) {
-            Ok(_) => println!("PlotMetaJunction generated successfully."),
+            Ok(_) => info!("PlotMetaJunction generated successfully."),
             Err(e) => {
-                eprintln!("Error: {}", e);
-                eprintln!("PlotMetaJunction generation failed. Please check the error messages above.");
-                std::process::exit(1);
+                error!("PlotMetaJunction generation failed. Please check the error messages above.");
+                exit_with_error("plotMetaJunction", e);
             }
         }
     }
@@ -435,13 +795,13 @@ fn main() {
 if let Some(matches) = matches.subcommand_matches("plotMetaCodon") {
     let codon_flag = if matches.get_flag("start_codon") { "-s" } else { "-e" };
 
-    let current_dir = std::env::current_dir().expect("Failed to get current directory");
+    let current_dir = std::env::current_dir().unwrap_or_else(|e| exit_with_error("plotMetaCodon", e.into()));
     let input_file = current_dir.join(matches.get_one::<String>("input_file").unwrap())
         .canonicalize()
-        .expect("Failed to canonicalize input file path");
+        .unwrap_or_else(|e| exit_with_error("plotMetaCodon", e.into()));
     let output_file_path = PathBuf::from(matches.get_one::<String>("output_file").unwrap());
     let output_file = output_file_path.absolutize()
-        .expect("Failed to absolutize output file path");
+        .unwrap_or_else(|e| exit_with_error("plotMetaCodon", e.into()));
 
     let mut args: Vec<String> = vec![
         input_file.to_string_lossy().into_owned(),
@@ -460,7 +820,7 @@ if let Some(matches) = matches.subcommand_matches("plotMetaCodon") {
     if let Some(table) = matches.get_one::<String>("save_table") {
         let save_table_path_buf = PathBuf::from(table);
         let save_table_path = save_table_path_buf.absolutize()
-            .expect("Failed to absolutize save table path");    
+            .unwrap_or_else(|e| exit_with_error("plotMetaCodon", e.into()));    
         args.extend_from_slice(&["-o".to_string(), save_table_path.to_string_lossy().into_owned()]);
     }
 
@@ -468,15 +828,13 @@ if let Some(matches) = matches.subcommand_matches("plotMetaCodon") {
         args.push("-R".to_string());
     }
 
-    println!("Arguments being passed to R2_plotMetaCodon.R:");
-    println!("Rscript R2_plotMetaCodon.R {}", args.join(" "));
+    info!("Arguments being passed to R2_plotMetaCodon.R: Rscript R2_plotMetaCodon.R {}", args.join(" "));
 
     match generate_r_plots("R2_plotMetaCodon.R", &args, None) {
-        Ok(_) => println!("PlotMetaCodon generated successfully."),
+        Ok(_) => info!("PlotMetaCodon generated successfully."),
         Err(e) => {
-            eprintln!("Error: {}", e);
-            eprintln!("PlotMetaCodon generation failed. Please check the error messages above.");
-            std::process::exit(1);
+            error!("PlotMetaCodon generation failed. Please check the error messages above.");
+            exit_with_error("plotMetaCodon", e);
         }
     }
 }
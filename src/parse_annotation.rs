@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use multimap::MultiMap;
 use bio::io::gff;
+use log::{debug, error, warn};
 
 // Define exon structure
 #[derive(Debug, PartialEq, Clone)] // Add Clone trait
@@ -88,7 +89,7 @@ pub fn read_gtf_file(gtf_file: &str) -> HashMap<String, Transcript> {
         let record = match record {
             Ok(record) => record,
             Err(err) => {
-                eprintln!("Error reading record: {}", err);
+                error!("Error reading record: {}", err);
                 continue;
             }
         };
@@ -102,8 +103,8 @@ pub fn read_gtf_file(gtf_file: &str) -> HashMap<String, Transcript> {
         let transcript_id_with_version = match attributes.get("transcript_id") {
             Some(id) => id,
             None => {
-                eprintln!(
-                    "Error: Missing 'transcript_id' in record: {:?}. Skipping...",
+                error!(
+                    "Missing 'transcript_id' in record: {:?}. Skipping...",
                     record
                 );
                 continue;
@@ -163,8 +164,8 @@ pub fn read_gtf_file(gtf_file: &str) -> HashMap<String, Transcript> {
             }
             "transcript" => {
                 if !encountered_transcripts.insert(transcript_id.clone()) {
-                    eprintln!(
-                        "Warning: More than one transcript with the same name '{}' found. Please check the GTF file.",
+                    warn!(
+                        "More than one transcript with the same name '{}' found. Please check the GTF file.",
                         transcript_id
                     );
                 }
@@ -197,9 +198,9 @@ pub fn read_gtf_file(gtf_file: &str) -> HashMap<String, Transcript> {
     }
 
     if !ignored_features.is_empty() {
-        eprintln!("Ignored the following annotation features:");
+        warn!("Ignored the following annotation features:");
         for (feature_type, count) in ignored_features {
-            eprintln!("- {}: {} occurrences", feature_type, count);
+            warn!("- {}: {} occurrences", feature_type, count);
         }
     }
 
@@ -268,8 +269,8 @@ pub fn read_gff_file(gff_file: &str) -> HashMap<String, Transcript> {
                         transcript.exons.push(exon.clone());
                         true
                     } else {
-                        eprintln!(
-                            "Warning: Transcript {} has exons on different chromosomes. Removing transcript from object.",
+                        warn!(
+                            "Transcript {} has exons on different chromosomes. Removing transcript from object.",
                             transcript.transcript_id
                         );
                         false
@@ -315,7 +316,7 @@ pub fn read_gff_file(gff_file: &str) -> HashMap<String, Transcript> {
         }
     }
 
-    println!("Size of the transcripts hashmap: {}", transcripts.len()); 
+    debug!("Size of the transcripts hashmap: {}", transcripts.len()); 
     transcripts
 
 }
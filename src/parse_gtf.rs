@@ -3,10 +3,10 @@ use multimap::MultiMap;
 use bio::io::gff;
 use rayon::prelude::*;
 use std::io::{BufRead, BufReader};
-use std::fs::File;
 use std::io::Cursor;
 use std::sync::Arc;
-use log::warn;
+use log::{info, warn};
+use crate::error::{R2dError, open_file};
 
 // exon struct 
 #[derive(Debug, PartialEq, Clone, Default)]
@@ -69,17 +69,41 @@ impl Default for Transcript {
     }
 }
 
+impl Transcript {
+    // exon features ordered 5' to 3' along the mature transcript
+    pub fn ordered_exons(&self) -> Vec<&Exon> {
+        let mut exons: Vec<&Exon> = self.exons.iter()
+            .filter(|exon| exon.feature.as_deref() == Some("exon"))
+            .collect();
+        if self.strand.as_deref() == Some("-") {
+            exons.sort_by_key(|exon| std::cmp::Reverse(exon.start));
+        } else {
+            exons.sort_by_key(|exon| exon.start);
+        }
+        exons
+    }
+
+    // whether any feature of the transcript carries the tag, e.g. Ensembl_canonical
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.exons.iter().any(|exon| exon.attributes.get("tag").is_some_and(|tags| tags.split(',').any(|t| t == tag)))
+    }
+}
+
+// repeated tags (e.g. tag "basic"; tag "Ensembl_canonical") are kept as a comma separated list, other
+// repeated attributes keep their first value
 pub fn parse_gff_attributes(attributes: &MultiMap<String, String>) -> HashMap<String, String> {
-    attributes.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    attributes.iter_all()
+        .map(|(k, v)| (k.clone(), if k == "tag" { v.join(",") } else { v[0].clone() }))
+        .collect()
 }
   
 fn find_biotype(attributes: &HashMap<String, String>, keys: &[&str]) -> Option<String> {
     keys.iter().find_map(|key| attributes.get(*key)).cloned()
 }
 
-pub fn read_annotation_file(file_path: &str, is_gtf: bool, has_version: bool) -> Result<HashMap<String, Transcript>, Box<dyn std::error::Error>> {
+pub fn read_annotation_file(file_path: &str, is_gtf: bool, has_version: bool) -> Result<HashMap<String, Transcript>, R2dError> {
 
-    let file = File::open(file_path)?;
+    let file = open_file(file_path)?;
     let reader = BufReader::with_capacity(512 * 1024, file); // buffer size of 512KB
     let biotype_keys = Arc::new(vec!["transcript_biotype", "transcript_type", "gene_type", "gene_biotype"]);
 
@@ -176,7 +200,11 @@ pub fn read_annotation_file(file_path: &str, is_gtf: bool, has_version: bool) ->
 
     // report skipped PAR genes 
     if !skipped_par_genes.is_empty() {
-        eprintln!("Skipped {} unique genes with '_PAR_' in their identifiers while parsing GTF", skipped_par_genes.len());
+        info!("Skipped {} unique genes with '_PAR_' in their identifiers while parsing GTF", skipped_par_genes.len());
+    }
+
+    if transcripts.is_empty() {
+        return Err(R2dError::AnnotationParse(format!("No transcripts with a transcript_id attribute found in {}", file_path)));
     }
     
     Ok(transcripts)
@@ -404,6 +432,18 @@ mod tests {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    #[test]
+    fn test_parse_gff_attributes() {
+        let mut attributes = MultiMap::new();
+        for (key, value) in [("tag", "basic"), ("ont", "PGO:0000004"), ("tag", "Ensembl_canonical"), ("ont", "PGO:0000005"), ("gene_id", "G1")] {
+            attributes.insert(key.to_string(), value.to_string());
+        }
+        let parsed = parse_gff_attributes(&attributes);
+        assert_eq!(parsed["tag"], "basic,Ensembl_canonical");
+        assert_eq!(parsed["ont"], "PGO:0000004");
+        assert_eq!(parsed["gene_id"], "G1");
+    }
+
     #[test]
     fn test_read_gtf_file() {
         init();
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::sync::Mutex;
+use bio::io::fasta;
+use crate::error::{R2dError, open_file};
+use crate::parse_gtf::Transcript;
+
+// seekable FASTA stream behind the genome index, a file or an in-memory buffer
+pub trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+pub type GenomeReader = fasta::IndexedReader<Box<dyn ReadSeek>>;
+
+// opens a new stream on the genome FASTA
+type GenomeOpener = Box<dyn Fn() -> Option<Box<dyn ReadSeek>> + Send + Sync>;
+
+// exons of each transcript as 1-based, inclusive genomic intervals in transcript order, built once rather
+// than for every site
+type ExonLayouts = HashMap<String, Vec<(u64, u64)>>;
+
+// one genome reader per rayon worker, opened on first use and sharing the parsed index, so that annotating
+// threads never wait on each other; threads outside the pool share the last reader
+pub struct GenomeReaders {
+    index: fasta::Index,
+    open: GenomeOpener,
+    readers: Vec<Mutex<Option<GenomeReader>>>,
+}
+
+impl GenomeReaders {
+    fn new(index: fasta::Index, open: GenomeOpener) -> Self {
+        let readers = (0..=rayon::current_num_threads()).map(|_| Mutex::new(None)).collect();
+        GenomeReaders { index, open, readers }
+    }
+
+    fn with_reader<T>(&self, read: impl FnOnce(&mut GenomeReader) -> Option<T>) -> Option<T> {
+        let last = self.readers.len() - 1;
+        let slot = rayon::current_thread_index().map_or(last, |index| index.min(last));
+        let mut reader = self.readers[slot].lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if reader.is_none() {
+            *reader = Some(fasta::IndexedReader::with_index((self.open)()?, self.index.clone()));
+        }
+        read(reader.as_mut()?)
+    }
+}
+
+// reference sequences used to extract the sequence context around each site:
+// either one record per transcript, or an indexed genome that is spliced through the exon structure
+pub enum SequenceSource {
+    Transcriptome(HashMap<String, Vec<u8>>),
+    Genome(Box<GenomeReaders>, ExonLayouts),
+}
+
+// read all records of a transcriptome fasta file into memory, keyed on the record id
+// transcript ids are trimmed at the first '|' (GENCODE headers) and optionally stripped of their version
+pub fn read_fasta(file_path: &str, has_version: bool) -> Result<HashMap<String, Vec<u8>>, R2dError> {
+    let reader = fasta::Reader::new(open_file(file_path)?);
+    let mut sequences = HashMap::new();
+
+    for record in reader.records() {
+        let record = record.map_err(|e| R2dError::AnnotationParse(format!("Invalid FASTA record in {}: {}", file_path, e)))?;
+        let id = record.id().split('|').next().unwrap_or_default();
+        let id = if has_version { id } else { id.split('.').next().unwrap_or_default() };
+        sequences.insert(id.to_string(), record.seq().to_ascii_uppercase());
+    }
+
+    Ok(sequences)
+}
+
+impl SequenceSource {
+    pub fn from_transcriptome(file_path: &str, has_version: bool) -> Result<Self, R2dError> {
+        Ok(SequenceSource::Transcriptome(read_fasta(file_path, has_version)?))
+    }
+
+    // the genome FASTA must be indexed with samtools faidx, so that only the bases around each site are read
+    pub fn from_genome(file_path: &str, transcripts: &HashMap<String, Transcript>) -> Result<Self, R2dError> {
+        let index_path = format!("{}.fai", file_path);
+        let index = open_file(&index_path).map_err(|_| R2dError::AnnotationParse(format!(
+            "Missing FASTA index {}, create it with 'samtools faidx {}'", index_path, file_path
+        )))?;
+        let index = fasta::Index::new(index)
+            .map_err(|e| R2dError::AnnotationParse(format!("Invalid FASTA index {}: {}", index_path, e)))?;
+        // fail early on an unreadable genome, rather than reporting NA for every site
+        open_file(file_path)?;
+        let file_path = file_path.to_string();
+        let open: GenomeOpener = Box::new(move || open_file(&file_path).ok().map(|file| Box::new(file) as Box<dyn ReadSeek>));
+        Ok(Self::from_genome_opener(index, open, transcripts.values()))
+    }
+
+    fn from_genome_opener<'a>(index: fasta::Index, open: GenomeOpener, transcripts: impl Iterator<Item = &'a Transcript>) -> Self {
+        let layouts = transcripts
+            .map(|transcript| {
+                let exons = transcript.ordered_exons().iter().map(|exon| (exon.start, exon.end)).collect();
+                (transcript.transcript_id.clone(), exons)
+            })
+            .collect();
+        SequenceSource::Genome(Box::new(GenomeReaders::new(index, open)), layouts)
+    }
+
+    // fetch the transcript sequence in the 0-based, half-open interval [start, end)
+    // positions outside of the transcript are padded with 'N', so the result always has length end - start
+    pub fn fetch(&self, transcript: &Transcript, start: i64, end: i64) -> Option<Vec<u8>> {
+        match self {
+            SequenceSource::Transcriptome(sequences) => {
+                let sequence = sequences.get(&transcript.transcript_id)?;
+                Some(pad_window(sequence, 0, start, end))
+            }
+            SequenceSource::Genome(readers, layouts) => {
+                let exons = layouts.get(&transcript.transcript_id)?;
+                // only splice the part of the transcript overlapping the window
+                let tx_len = exons.iter().map(|(exon_start, exon_end)| exon_end - exon_start + 1).sum::<u64>() as i64;
+                let from = start.clamp(0, tx_len);
+                let to = end.clamp(0, tx_len);
+                let spliced = readers.with_reader(|reader| splice_genomic_sequence(reader, transcript, exons, from as u64, to as u64))?;
+                Some(pad_window(&spliced, from, start, end))
+            }
+        }
+    }
+}
+
+// read the bases of a chromosome in the 0-based, half-open interval [start, end), None when it is not in the genome
+fn read_genome(reader: &mut GenomeReader, chromosome: &str, start: u64, end: u64) -> Option<Vec<u8>> {
+    let mut bases = Vec::with_capacity((end - start) as usize);
+    reader.fetch(chromosome, start, end).ok()?;
+    reader.read(&mut bases).ok()?;
+    if bases.len() as u64 != end - start {
+        return None;
+    }
+    bases.make_ascii_uppercase();
+    Some(bases)
+}
+
+// slice [start, end) out of a sequence that begins at transcript position `offset`, padding with 'N'
+fn pad_window(sequence: &[u8], offset: i64, start: i64, end: i64) -> Vec<u8> {
+    (start..end)
+        .map(|pos| {
+            if pos < offset {
+                b'N'
+            } else {
+                sequence.get((pos - offset) as usize).copied().unwrap_or(b'N')
+            }
+        })
+        .collect()
+}
+
+// build the mature transcript sequence in [tx_start, tx_end) by walking the exons in transcript order
+fn splice_genomic_sequence(reader: &mut GenomeReader, transcript: &Transcript, exons: &[(u64, u64)], tx_start: u64, tx_end: u64) -> Option<Vec<u8>> {
+    let is_minus = transcript.strand.as_deref() == Some("-");
+    let mut bases = Vec::with_capacity((tx_end - tx_start) as usize);
+    let mut exon_tx_start = 0;
+
+    for &(exon_start, exon_end) in exons {
+        let exon_tx_end = exon_tx_start + exon_end - exon_start + 1;
+        let from = tx_start.max(exon_tx_start);
+        let to = tx_end.min(exon_tx_end);
+
+        if from < to {
+            // offsets within the exon, in transcript direction
+            let (offset_from, offset_to) = (from - exon_tx_start, to - exon_tx_start);
+            if is_minus {
+                // the 5' end of a minus strand exon is its genomic end (1-based, inclusive)
+                let segment = read_genome(reader, &transcript.chromosome, exon_end - offset_to, exon_end - offset_from)?;
+                bases.extend(segment.iter().rev().map(|&base| complement(base)));
+            } else {
+                let segment = read_genome(reader, &transcript.chromosome, exon_start - 1 + offset_from, exon_start - 1 + offset_to)?;
+                bases.extend_from_slice(&segment);
+            }
+        }
+        exon_tx_start = exon_tx_end;
+    }
+
+    Some(bases)
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        _ => b'N',
+    }
+}
+
+// check whether a base is one of the nucleotides encoded by an IUPAC code
+fn iupac_matches(code: u8, base: u8) -> bool {
+    let base = if base == b'U' { b'T' } else { base };
+    let allowed: &[u8] = match code.to_ascii_uppercase() {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' | b'U' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => return true,
+        _ => b"",
+    };
+    allowed.contains(&base)
+}
+
+pub fn is_valid_motif(motif: &str) -> bool {
+    !motif.is_empty() && motif.bytes().all(|code| b"ACGTURYSWKMBDHVN".contains(&code.to_ascii_uppercase()))
+}
+
+// check whether a sequence matches an IUPAC motif of the same length
+pub fn matches_motif(sequence: &[u8], motif: &str) -> bool {
+    sequence.len() == motif.len() && motif.bytes().zip(sequence).all(|(code, &base)| iupac_matches(code, base))
+}
+
+// fraction of G and C among the called bases of a sequence
+pub fn gc_content(sequence: &[u8]) -> Option<f64> {
+    let called = sequence.iter().filter(|&&base| base != b'N').count();
+    if called == 0 {
+        return None;
+    }
+    let gc = sequence.iter().filter(|&&base| base == b'G' || base == b'C').count();
+    Some(gc as f64 / called as f64)
+}
+
+// amino acids of the standard genetic code, for codons ordered by bases T, C, A, G
+const GENETIC_CODE: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+// translate a codon with the standard genetic code, giving '*' for stop codons
+// codons with ambiguous or padding bases translate to 'X'
+pub fn translate_codon(codon: &[u8]) -> u8 {
+    if codon.len() != 3 {
+        return b'X';
+    }
+    let mut index = 0;
+    for &base in codon {
+        let code = match base.to_ascii_uppercase() {
+            b'T' | b'U' => 0,
+            b'C' => 1,
+            b'A' => 2,
+            b'G' => 3,
+            _ => return b'X',
+        };
+        index = index * 4 + code;
+    }
+    GENETIC_CODE[index]
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_gtf::Exon;
+
+    fn create_test_transcript(strand: &str, exons: Vec<(u64, u64)>) -> Transcript {
+        Transcript {
+            transcript_id: "tx1".to_string(),
+            strand: Some(strand.to_string()),
+            chromosome: "chr1".to_string(),
+            exons: exons.into_iter().map(|(start, end)| Exon {
+                start,
+                end,
+                length: end - start + 1,
+                feature: Some("exon".to_string()),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    // indexed genome for the transcript; 1-based positions 1-10 are AAAAACCCCC, 11-20 are ggggGTTTTT,
+    // with a soft-masked stretch and lines of 8 bases
+    fn create_test_genome(transcript: &Transcript) -> SequenceSource {
+        let index = fasta::Index::new("chr1\t20\t6\t8\t9\n".as_bytes()).unwrap();
+        let open: GenomeOpener = Box::new(|| Some(Box::new(std::io::Cursor::new(b">chr1\nAAAAACCC\nCCggggGT\nTTTT\n".to_vec())) as Box<dyn ReadSeek>));
+        SequenceSource::from_genome_opener(index, open, std::iter::once(transcript))
+    }
+
+    #[test]
+    fn test_fetch_genome_across_junction() {
+        // exons 3-6 (AAAC) and 14-17 (GGTT)
+        let transcript = create_test_transcript("+", vec![(14, 17), (3, 6)]);
+        let source = create_test_genome(&transcript);
+        assert_eq!(source.fetch(&transcript, 2, 6).unwrap(), b"ACGG".to_vec());
+        assert_eq!(source.fetch(&transcript, -2, 2).unwrap(), b"NNAA".to_vec());
+        assert_eq!(source.fetch(&transcript, 6, 10).unwrap(), b"TTNN".to_vec());
+    }
+
+    #[test]
+    fn test_fetch_genome_minus_strand() {
+        // transcript reads revcomp(GGTT) then revcomp(AAAC) = AACC GTTT
+        let transcript = create_test_transcript("-", vec![(3, 6), (14, 17)]);
+        let source = create_test_genome(&transcript);
+        assert_eq!(source.fetch(&transcript, 0, 8).unwrap(), b"AACCGTTT".to_vec());
+        assert_eq!(source.fetch(&transcript, 3, 5).unwrap(), b"CG".to_vec());
+    }
+
+    #[test]
+    fn test_fetch_genome_in_parallel() {
+        use rayon::prelude::*;
+        let transcript = create_test_transcript("+", vec![(14, 17), (3, 6)]);
+        let source = create_test_genome(&transcript);
+        let windows: Vec<Vec<u8>> = (0..1000).into_par_iter().map(|i| source.fetch(&transcript, i % 8, i % 8 + 2).unwrap()).collect();
+        for (i, window) in windows.iter().enumerate() {
+            assert_eq!(window, &b"AAACGGTTNN"[i % 8..i % 8 + 2]);
+        }
+    }
+
+    #[test]
+    fn test_fetch_genome_missing_sequence() {
+        let transcript = create_test_transcript("+", vec![(3, 6)]);
+        let source = create_test_genome(&transcript);
+        let mut other_chromosome = create_test_transcript("+", vec![(3, 6)]);
+        other_chromosome.chromosome = "chr2".to_string();
+        assert_eq!(source.fetch(&other_chromosome, 0, 2), None);
+
+        // exons running past the chromosome end
+        let transcript = create_test_transcript("+", vec![(18, 25)]);
+        let source = create_test_genome(&transcript);
+        assert_eq!(source.fetch(&transcript, 0, 2).unwrap(), b"TT".to_vec());
+        assert_eq!(source.fetch(&transcript, 0, 5), None);
+    }
+
+    #[test]
+    fn test_fetch_transcriptome() {
+        let mut sequences = HashMap::new();
+        sequences.insert("tx1".to_string(), b"GGACTGG".to_vec());
+        let source = SequenceSource::Transcriptome(sequences);
+        let transcript = create_test_transcript("+", vec![(1, 7)]);
+        assert_eq!(source.fetch(&transcript, 1, 6).unwrap(), b"GACTG".to_vec());
+        assert_eq!(source.fetch(&transcript, 5, 9).unwrap(), b"GGNN".to_vec());
+    }
+
+    #[test]
+    fn test_matches_motif() {
+        assert!(matches_motif(b"GGACT", "DRACH"));
+        assert!(matches_motif(b"AGACA", "DRACH"));
+        assert!(!matches_motif(b"CGACT", "DRACH"));
+        assert!(!matches_motif(b"GGACG", "DRACH"));
+        assert!(!matches_motif(b"GGAC", "DRACH"));
+        assert!(matches_motif(b"GGACU", "drach"));
+    }
+
+    #[test]
+    fn test_is_valid_motif() {
+        assert!(is_valid_motif("DRACH"));
+        assert!(!is_valid_motif("DRXCH"));
+        assert!(!is_valid_motif(""));
+    }
+
+    #[test]
+    fn test_gc_content() {
+        assert_eq!(gc_content(b"GGCC"), Some(1.0));
+        assert_eq!(gc_content(b"NNGA"), Some(0.5));
+        assert_eq!(gc_content(b"NNNN"), None);
+    }
+
+    #[test]
+    fn test_translate_codon() {
+        assert_eq!(translate_codon(b"ATG"), b'M');
+        assert_eq!(translate_codon(b"TGG"), b'W');
+        assert_eq!(translate_codon(b"GGC"), b'G');
+        assert_eq!(translate_codon(b"uaa"), b'*');
+        assert_eq!(translate_codon(b"TGA"), b'*');
+        assert_eq!(translate_codon(b"ANG"), b'X');
+        assert_eq!(translate_codon(b"AT"), b'X');
+    }
+}
@@ -0,0 +1,51 @@
+use std::env;
+use log::debug;
+use crate::error::R2dError;
+
+// environment variable read when --threads is not given, e.g. set from the scheduler allocation
+pub const THREADS_ENV_VAR: &str = "R2D_THREADS";
+
+pub fn parse_threads(value: &str, source: &str) -> Result<usize, R2dError> {
+    match value.trim().parse::<usize>() {
+        Ok(threads) if threads > 0 => Ok(threads),
+        _ => Err(R2dError::InputParse(format!("Invalid number of threads in {}: {}", source, value))),
+    }
+}
+
+// number of worker threads from --threads, then R2D_THREADS, or None to use all cores
+pub fn threads_from_matches(matches: &clap::ArgMatches) -> Result<Option<usize>, R2dError> {
+    if let Some(threads) = matches.get_one::<String>("threads") {
+        return parse_threads(threads, "--threads").map(Some);
+    }
+    match env::var(THREADS_ENV_VAR) {
+        Ok(threads) if !threads.trim().is_empty() => parse_threads(&threads, THREADS_ENV_VAR).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// size the global rayon pool used by the GTF parsing, liftover and annotate steps of every subcommand
+pub fn init_thread_pool(matches: &clap::ArgMatches) -> Result<(), R2dError> {
+    if let Some(threads) = threads_from_matches(matches)? {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+            .map_err(|e| R2dError::Io(std::io::Error::other(format!("cannot start {} threads: {}", threads, e))))?;
+    }
+    debug!("Using {} threads", rayon::current_num_threads());
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_threads() {
+        assert_eq!(parse_threads("4", "--threads").unwrap(), 4);
+        assert_eq!(parse_threads(" 2\n", THREADS_ENV_VAR).unwrap(), 2);
+
+        let error = parse_threads("0", "--threads").unwrap_err();
+        assert_eq!(error.exit_code(), 5);
+        assert_eq!(error.to_string(), "Input parse error: Invalid number of threads in --threads: 0");
+        assert!(parse_threads("all", THREADS_ENV_VAR).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use crate::bed::for_each_bed_record;
+use crate::error::{R2dError, open_file};
+use crate::input::named_file;
+
+// features of one chromosome, indexed for overlap queries, with their starts and ends sorted separately for
+// the nearest feature on either side of a site
+#[derive(Default)]
+struct ChromosomeFeatures {
+    intervals: ArrayBackedIntervalTree<u64, ()>,
+    starts: Vec<u64>,
+    ends: Vec<u64>,
+}
+
+impl ChromosomeFeatures {
+    fn index(&mut self) {
+        self.intervals.index();
+        self.starts.sort_unstable();
+        self.ends.sort_unstable();
+    }
+
+    // distance from the 0-based site to the nearest feature, 0 when a feature contains it
+    fn nearest_distance(&self, position: u64) -> Option<u64> {
+        if !self.intervals.find(position..position + 1).is_empty() {
+            return Some(0);
+        }
+        // last feature ending at or before the site, and first feature starting after it
+        let upstream = self.ends.partition_point(|&end| end <= position).checked_sub(1)
+            .map(|i| position - (self.ends[i] - 1));
+        let downstream = self.starts.get(self.starts.partition_point(|&start| start <= position))
+            .map(|&start| start - position);
+        match (upstream, downstream) {
+            (Some(upstream), Some(downstream)) => Some(upstream.min(downstream)),
+            (upstream, downstream) => upstream.or(downstream),
+        }
+    }
+}
+
+// genomic features (e.g. repeats, conserved elements, ENCODE peaks) read from a BED file, keyed on chromosome
+pub struct GenomicTrack {
+    pub label: String,
+    chromosomes: HashMap<String, ChromosomeFeatures>,
+}
+
+impl GenomicTrack {
+    pub fn from_file(label: &str, file_path: &str) -> Result<Self, R2dError> {
+        let reader = BufReader::new(open_file(file_path)?);
+        Self::from_reader(label, file_path, reader)
+    }
+
+    pub fn from_reader<R: BufRead>(label: &str, file_path: &str, reader: R) -> Result<Self, R2dError> {
+        let mut chromosomes: HashMap<String, ChromosomeFeatures> = HashMap::new();
+
+        for_each_bed_record(file_path, reader, |record| {
+            let features = chromosomes.entry(record.chrom.to_string()).or_default();
+            features.intervals.insert(record.start..record.end, ());
+            features.starts.push(record.start);
+            features.ends.push(record.end);
+        })?;
+
+        chromosomes.values_mut().for_each(|features| features.index());
+
+        Ok(GenomicTrack { label: label.to_string(), chromosomes })
+    }
+
+    // overlap flag and distance to the nearest feature of a 0-based genomic site, NA on chromosomes without features
+    pub fn fields(&self, chromosome: &str, position: u64) -> [String; 2] {
+        match self.chromosomes.get(chromosome).and_then(|features| features.nearest_distance(position)) {
+            Some(distance) => [if distance == 0 { "TRUE" } else { "FALSE" }.to_string(), distance.to_string()],
+            None => ["FALSE".to_string(), "NA".to_string()],
+        }
+    }
+
+    pub fn columns(&self) -> [String; 2] {
+        [format!("{}_overlap", self.label), format!("{}_dist", self.label)]
+    }
+}
+
+// tracks given with --track, each as NAME=BED or a BED file named after its file name
+pub fn tracks_from_matches(matches: &clap::ArgMatches) -> Result<Vec<GenomicTrack>, R2dError> {
+    matches.get_many::<String>("track").unwrap_or_default()
+        .map(|spec| {
+            let (label, file_path) = named_file(spec);
+            GenomicTrack::from_file(&label, &file_path)
+        })
+        .collect()
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_fields() {
+        let bed = "browser position chr1:1-1000\n# repeats\nchr1\t100\t200\tAluY\nchr1\t150\t400\tL1\nchr1\t600\t700\nchr2\t10\t20\n";
+        let track = GenomicTrack::from_reader("repeats", "repeats.bed", bed.as_bytes()).unwrap();
+
+        assert_eq!(track.columns(), ["repeats_overlap", "repeats_dist"]);
+        assert_eq!(track.fields("chr1", 50), ["FALSE", "50"]);
+        assert_eq!(track.fields("chr1", 100), ["TRUE", "0"]);
+        assert_eq!(track.fields("chr1", 399), ["TRUE", "0"]);
+        assert_eq!(track.fields("chr1", 400), ["FALSE", "1"]);
+        assert_eq!(track.fields("chr1", 550), ["FALSE", "50"]);
+        assert_eq!(track.fields("chr1", 800), ["FALSE", "101"]);
+        assert_eq!(track.fields("chr3", 800), ["FALSE", "NA"]);
+    }
+
+    #[test]
+    fn test_malformed_track() {
+        let error = GenomicTrack::from_reader("repeats", "repeats.bed", "chr1\t10\n".as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "Input parse error: repeats.bed line 1: missing column 3 (found 2 columns)");
+        assert!(GenomicTrack::from_reader("repeats", "repeats.bed", "chr1\t20\t10\n".as_bytes()).is_err());
+    }
+}